@@ -0,0 +1,73 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rust_decimal_macros::dec;
+use tokio::runtime::Runtime;
+use transactions::*;
+
+const RECORDS: u32 = 200_000;
+const CLIENTS: u32 = 10_000;
+
+/// Every ten tx ids belong to one client: seven deposits, a withdrawal, and a
+/// dispute and resolve of the first deposit.
+fn records() -> Vec<RawTransaction> {
+    (0..RECORDS)
+        .map(|tx| {
+            let first = tx - tx % 10;
+            let (transaction_type, reference, amount) = match tx % 10 {
+                7 => (TransactionTypes::Withdrawal, tx, Some(dec!(0.5))),
+                8 => (TransactionTypes::Dispute, first, None),
+                9 => (TransactionTypes::Resolve, first, None),
+                _ => (TransactionTypes::Deposit, tx, Some(dec!(10.25))),
+            };
+
+            RawTransaction {
+                transaction_type: Some(transaction_type),
+                client: Some((tx / 10 % CLIENTS) as u16),
+                tx: Some(reference),
+                amount,
+            }
+        })
+        .collect()
+}
+
+fn single(records: &[RawTransaction]) -> Transactor {
+    let mut transaction_processor = Transactor::new();
+    for record in records {
+        let _ = transaction_processor.process_a_record(*record);
+    }
+    transaction_processor
+}
+
+async fn sharded(shards: usize, records: &[RawTransaction]) -> Transactor {
+    let (mut engine, mut rejected) = ShardedEngine::spawn(shards, Transactor::new);
+    for (line, record) in records.iter().enumerate() {
+        engine.process(None, line as u64, *record).await;
+    }
+
+    let mut transaction_processor = Transactor::new();
+    engine.finish(&mut transaction_processor).await.unwrap();
+    while rejected.recv().await.is_some() {}
+    transaction_processor
+}
+
+fn throughput(c: &mut Criterion) {
+    let records = records();
+    let runtime = Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("process");
+    group.throughput(Throughput::Elements(u64::from(RECORDS)));
+    group.sample_size(20);
+
+    group.bench_function("single", |b| b.iter(|| single(&records)));
+    for shards in [1, 2, 4, 8] {
+        group.bench_with_input(
+            BenchmarkId::new("sharded", shards),
+            &shards,
+            |b, &shards| b.iter(|| runtime.block_on(sharded(shards, &records))),
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
@@ -0,0 +1,263 @@
+use std::{io::Write, str::FromStr, sync::Arc};
+
+use arrow_array::{
+    cast::AsArray,
+    types::{UInt16Type, UInt32Type, UInt64Type},
+    Array, ArrayRef, BooleanArray, Decimal128Array, RecordBatch, UInt16Array,
+};
+use arrow_cast::{cast, cast_with_options, CastOptions};
+use arrow_schema::{ArrowError, DataType, Field, Schema};
+use parquet::{
+    arrow::{arrow_reader::ParquetRecordBatchReader, ArrowWriter},
+    errors::ParquetError,
+    file::reader::ChunkReader,
+};
+use rust_decimal::Decimal;
+use serde_json::Value;
+
+use crate::{ingest::Row, AccountRow, IngestError, RawTransaction};
+
+/// The scale amounts are written to Parquet with, which is the precision of the
+/// account report.
+const SCALE: u32 = 4;
+
+/// The number of rows read or written at a time.
+const BATCH_SIZE: usize = 1024;
+
+/// The records of a Parquet file, a batch at a time.
+///
+/// The file has the same columns as a CSV input: `type` and `amount` are read
+/// as strings (so any numeric or decimal amount column works), `client`, `tx`
+/// and an optional `timestamp` as unsigned integers. A `client` or `tx` which
+/// does not fit its type rejects its row as out of range, a `timestamp` which
+/// does not is read as missing. Rows are numbered from 1 across the whole file,
+/// in place of a line.
+pub(crate) struct ParquetRows {
+    batches: ParquetRecordBatchReader,
+    batch: Option<Columns>,
+    row: u64,
+}
+
+struct Columns {
+    transaction_type: ArrayRef,
+    client: Ids,
+    tx: Ids,
+    amount: ArrayRef,
+    timestamp: Option<ArrayRef>,
+    len: usize,
+    next: usize,
+}
+
+/// An id column, cast to its type. When a value in the batch is out of range
+/// the column is kept as read and cast a row at a time, so that only the rows
+/// with such a value are rejected.
+enum Ids {
+    Cast(ArrayRef),
+    Uncast(ArrayRef),
+}
+
+/// Casts which fail on a value that does not fit, rather than making it null.
+const STRICT: CastOptions = CastOptions {
+    safe: false,
+    format_options: arrow_cast::display::FormatOptions::new(),
+};
+
+impl ParquetRows {
+    pub(crate) fn new<T: ChunkReader + 'static>(input: T) -> Result<ParquetRows, IngestError> {
+        let batches =
+            ParquetRecordBatchReader::try_new(input, BATCH_SIZE).map_err(|e| malformed(0, e))?;
+
+        Ok(ParquetRows {
+            batches,
+            batch: None,
+            row: 0,
+        })
+    }
+
+    pub(crate) fn next(&mut self) -> Result<Option<Row>, IngestError> {
+        loop {
+            if let Some(columns) = &mut self.batch {
+                if columns.next < columns.len {
+                    let i = columns.next;
+                    columns.next += 1;
+                    self.row += 1;
+                    return columns.row(i, self.row).map(Some);
+                }
+            }
+
+            match self.batches.next() {
+                None => return Ok(None),
+                Some(batch) => {
+                    let batch = batch.map_err(|e| malformed(self.row + 1, e))?;
+                    self.batch =
+                        Some(Columns::new(&batch).map_err(|e| malformed(self.row + 1, e))?);
+                }
+            }
+        }
+    }
+}
+
+impl Columns {
+    fn new(batch: &RecordBatch) -> Result<Columns, ArrowError> {
+        let raw = |name: &str| {
+            batch
+                .column_by_name(name)
+                .cloned()
+                .ok_or_else(|| ArrowError::SchemaError(format!("missing column '{name}'")))
+        };
+        let column = |name: &str, data_type: &DataType| cast(&raw(name)?, data_type);
+
+        Ok(Columns {
+            transaction_type: column("type", &DataType::Utf8)?,
+            client: Ids::new(raw("client")?, &DataType::UInt16),
+            tx: Ids::new(raw("tx")?, &DataType::UInt32),
+            amount: column("amount", &DataType::Utf8)?,
+            timestamp: batch
+                .column_by_name("timestamp")
+                .map(|column| cast(column, &DataType::UInt64))
+                .transpose()?,
+            len: batch.num_rows(),
+            next: 0,
+        })
+    }
+
+    fn row(&self, i: usize, line: u64) -> Result<Row, IngestError> {
+        let malformed = |reason| IngestError::Malformed {
+            line,
+            byte: 0,
+            reason,
+        };
+
+        let transaction_type = match string(&self.transaction_type, i) {
+            None => None,
+            Some(name) => Some(
+                serde_json::from_value(Value::String(name.trim().to_string()))
+                    .map_err(|e| malformed(e.to_string()))?,
+            ),
+        };
+        let amount = match string(&self.amount, i).map(str::trim) {
+            None | Some("") => None,
+            Some(amount) => Some(
+                Decimal::from_str(amount)
+                    .map_err(|_| malformed(format!("invalid amount '{amount}'")))?,
+            ),
+        };
+
+        Ok(Row {
+            line,
+            byte: 0,
+            timestamp: self
+                .timestamp
+                .as_ref()
+                .and_then(|column| value::<UInt64Type>(column, i)),
+            record: RawTransaction {
+                transaction_type,
+                client: self
+                    .client
+                    .value::<UInt16Type>(i)
+                    .map_err(|e| malformed(format!("invalid client: {e}")))?,
+                tx: self
+                    .tx
+                    .value::<UInt32Type>(i)
+                    .map_err(|e| malformed(format!("invalid tx: {e}")))?,
+                amount,
+            },
+        })
+    }
+}
+
+impl Ids {
+    fn new(column: ArrayRef, data_type: &DataType) -> Ids {
+        match cast_with_options(&column, data_type, &STRICT) {
+            Ok(column) => Ids::Cast(column),
+            Err(_) => Ids::Uncast(column),
+        }
+    }
+
+    fn value<T: arrow_array::ArrowPrimitiveType>(
+        &self,
+        i: usize,
+    ) -> Result<Option<T::Native>, ArrowError> {
+        match self {
+            Ids::Cast(column) => Ok(value::<T>(column, i)),
+            Ids::Uncast(column) => Ok(value::<T>(
+                &cast_with_options(&column.slice(i, 1), &T::DATA_TYPE, &STRICT)?,
+                0,
+            )),
+        }
+    }
+}
+
+fn string(column: &ArrayRef, i: usize) -> Option<&str> {
+    let column = column.as_string::<i32>();
+    column.is_valid(i).then(|| column.value(i))
+}
+
+fn value<T: arrow_array::ArrowPrimitiveType>(column: &ArrayRef, i: usize) -> Option<T::Native> {
+    let column = column.as_primitive::<T>();
+    column.is_valid(i).then(|| column.value(i))
+}
+
+fn malformed(line: u64, error: impl ToString) -> IngestError {
+    IngestError::Malformed {
+        line,
+        byte: 0,
+        reason: error.to_string(),
+    }
+}
+
+/// Writes `rows` to `w` as a Parquet file with the columns of the account
+/// report, a batch of rows at a time. Amounts are decimals with a scale of
+/// four.
+pub(crate) fn write_parquet<W, I>(rows: I, w: W) -> Result<(), ParquetError>
+where
+    W: Write + Send,
+    I: IntoIterator<Item = AccountRow>,
+{
+    let amount = DataType::Decimal128(38, SCALE as i8);
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("client", DataType::UInt16, false),
+        Field::new("available", amount.clone(), false),
+        Field::new("held", amount.clone(), false),
+        Field::new("total", amount.clone(), false),
+        Field::new("locked", DataType::Boolean, false),
+    ]));
+
+    let mut writer = ArrowWriter::try_new(w, schema.clone(), None)?;
+    let mut rows = rows.into_iter().peekable();
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    while rows.peek().is_some() {
+        batch.clear();
+        batch.extend(rows.by_ref().take(BATCH_SIZE));
+        writer.write(&record_batch(&schema, &batch)?)?;
+    }
+    writer.close()?;
+    Ok(())
+}
+
+fn record_batch(schema: &Arc<Schema>, rows: &[AccountRow]) -> Result<RecordBatch, ArrowError> {
+    let amounts = |amount: fn(&AccountRow) -> Decimal| -> Result<ArrayRef, ArrowError> {
+        let column = Decimal128Array::from_iter_values(rows.iter().map(|row| {
+            // rescale keeps fewer places if the amount is too large for
+            // SCALE of them, so the mantissa is scaled up in an i128 instead
+            let amount = amount(row).round_dp(SCALE);
+            amount.mantissa() * 10i128.pow(SCALE - amount.scale())
+        }));
+        Ok(Arc::new(column.with_precision_and_scale(38, SCALE as i8)?))
+    };
+
+    RecordBatch::try_new(
+        schema.clone(),
+        vec![
+            Arc::new(UInt16Array::from_iter_values(
+                rows.iter().map(|row| row.client),
+            )),
+            amounts(|row| row.available)?,
+            amounts(|row| row.held)?,
+            amounts(|row| row.total)?,
+            Arc::new(BooleanArray::from_iter(
+                rows.iter().map(|row| Some(row.locked)),
+            )),
+        ],
+    )
+}
@@ -0,0 +1,113 @@
+use rust_decimal::Decimal;
+
+use crate::{RawTransaction, TransactionTypes, ValidationError};
+
+/// A record which has every field its type calls for, and only those. Made
+/// from a [`RawTransaction`] with `try_from`, which is the one place a record's
+/// fields are checked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Deposit {
+        client: u16,
+        tx: u32,
+        amount: Decimal,
+    },
+    Withdrawal {
+        client: u16,
+        tx: u32,
+        amount: Decimal,
+    },
+    Dispute {
+        client: u16,
+        tx: u32,
+    },
+    Resolve {
+        client: u16,
+        tx: u32,
+    },
+    Chargeback {
+        client: u16,
+        tx: u32,
+    },
+}
+
+impl Command {
+    pub fn transaction_type(&self) -> TransactionTypes {
+        match self {
+            Command::Deposit { .. } => TransactionTypes::Deposit,
+            Command::Withdrawal { .. } => TransactionTypes::Withdrawal,
+            Command::Dispute { .. } => TransactionTypes::Dispute,
+            Command::Resolve { .. } => TransactionTypes::Resolve,
+            Command::Chargeback { .. } => TransactionTypes::Chargeback,
+        }
+    }
+
+    pub fn client(&self) -> u16 {
+        match *self {
+            Command::Deposit { client, .. }
+            | Command::Withdrawal { client, .. }
+            | Command::Dispute { client, .. }
+            | Command::Resolve { client, .. }
+            | Command::Chargeback { client, .. } => client,
+        }
+    }
+
+    /// The tx of a deposit or withdrawal, or the tx a dispute, resolve or
+    /// chargeback refers to.
+    pub fn tx(&self) -> u32 {
+        match *self {
+            Command::Deposit { tx, .. }
+            | Command::Withdrawal { tx, .. }
+            | Command::Dispute { tx, .. }
+            | Command::Resolve { tx, .. }
+            | Command::Chargeback { tx, .. } => tx,
+        }
+    }
+
+    /// The amount of a deposit or withdrawal.
+    pub fn amount(&self) -> Option<Decimal> {
+        match *self {
+            Command::Deposit { amount, .. } | Command::Withdrawal { amount, .. } => Some(amount),
+            Command::Dispute { .. } | Command::Resolve { .. } | Command::Chargeback { .. } => None,
+        }
+    }
+}
+
+impl TryFrom<RawTransaction> for Command {
+    type Error = ValidationError;
+
+    fn try_from(record: RawTransaction) -> Result<Self, Self::Error> {
+        let transaction_type = record
+            .transaction_type
+            .ok_or(ValidationError::MissingType)?;
+        let client = record.client.ok_or(ValidationError::MissingClient)?;
+        let tx = record.tx.ok_or(ValidationError::MissingTx)?;
+
+        match (transaction_type, record.amount) {
+            (TransactionTypes::Deposit, Some(amount)) => {
+                Ok(Command::Deposit { client, tx, amount })
+            }
+            (TransactionTypes::Withdrawal, Some(amount)) => {
+                Ok(Command::Withdrawal { client, tx, amount })
+            }
+            (TransactionTypes::Deposit | TransactionTypes::Withdrawal, None) => {
+                Err(ValidationError::MissingAmount)
+            }
+            (_, Some(_)) => Err(ValidationError::UnexpectedAmount),
+            (TransactionTypes::Dispute, None) => Ok(Command::Dispute { client, tx }),
+            (TransactionTypes::Resolve, None) => Ok(Command::Resolve { client, tx }),
+            (TransactionTypes::Chargeback, None) => Ok(Command::Chargeback { client, tx }),
+        }
+    }
+}
+
+impl From<Command> for RawTransaction {
+    fn from(command: Command) -> Self {
+        RawTransaction {
+            transaction_type: Some(command.transaction_type()),
+            client: Some(command.client()),
+            tx: Some(command.tx()),
+            amount: command.amount(),
+        }
+    }
+}
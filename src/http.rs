@@ -0,0 +1,151 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Serialize;
+use tokio::sync::{mpsc::Sender, oneshot};
+
+use crate::{ingest::parse_json, validate::validate_fields, Outcome, Query, TransactionError};
+
+#[derive(Clone)]
+struct Consumer {
+    queries: Sender<Query>,
+    source: Arc<str>,
+}
+
+impl Consumer {
+    /// Sends the query made by `query` and waits for its answer.
+    async fn ask<T>(&self, query: impl FnOnce(oneshot::Sender<T>) -> Query) -> Result<T, Response> {
+        let (reply, answer) = oneshot::channel();
+        if self.queries.send(query(reply)).await.is_err() {
+            return Err(unavailable());
+        }
+        answer.await.map_err(|_| unavailable())
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+#[derive(Serialize)]
+struct Submitted {
+    outcome: Outcome,
+}
+
+#[derive(Serialize)]
+struct Dispute {
+    client: u16,
+    tx: u32,
+}
+
+fn error(status: StatusCode, error: impl ToString) -> Response {
+    let body = ErrorBody {
+        error: error.to_string(),
+    };
+    (status, Json(body)).into_response()
+}
+
+fn unavailable() -> Response {
+    error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "the consumer has shut down",
+    )
+}
+
+/// A REST API over the consumer which answers `queries`:
+///
+/// - `POST /transactions` processes the JSON record in the body and replies
+///   with its outcome, e.g. `{"outcome":"deposited"}`. A rejected record, or a
+///   body which is not one, gets `422` and `{"error": ...}`, and a record which
+///   could not be stored gets `500`.
+/// - `GET /accounts/{client}` replies with the client's account row, or `404`.
+/// - `GET /accounts/{client}/transactions` replies with every deposit and
+///   withdrawal the client made, along with its dispute state, or `404`.
+/// - `GET /disputes` replies with the client and tx of every open dispute.
+///
+/// Submitted records are tagged with the source `http`.
+pub fn router(queries: Sender<Query>) -> Router {
+    let consumer = Consumer {
+        queries,
+        source: "http".into(),
+    };
+
+    Router::new()
+        .route("/transactions", post(submit))
+        .route("/accounts/{client}", get(account))
+        .route("/accounts/{client}/transactions", get(transactions))
+        .route("/disputes", get(disputes))
+        .with_state(consumer)
+}
+
+async fn submit(State(consumer): State<Consumer>, body: Bytes) -> Response {
+    // Parsed here rather than by the Json extractor, so that a body which is
+    // not a record gets the same error body as any other rejection
+    let record = match parse_json(&body) {
+        Ok((_, record)) => record,
+        Err(e) => return error(StatusCode::UNPROCESSABLE_ENTITY, e),
+    };
+
+    // Like any other input, only well formed records reach the consumer
+    if let Err(e) = validate_fields(&record) {
+        return error(StatusCode::UNPROCESSABLE_ENTITY, e);
+    }
+
+    let source = consumer.source.clone();
+    let result = consumer
+        .ask(|reply| Query::Submit {
+            source,
+            record,
+            reply,
+        })
+        .await;
+
+    match result {
+        Ok(Ok(outcome)) => Json(Submitted { outcome }).into_response(),
+        // The ledger failing is no fault of the record
+        Ok(Err(e @ TransactionError::Storage(_))) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+        Ok(Err(e)) => error(StatusCode::UNPROCESSABLE_ENTITY, e),
+        Err(response) => response,
+    }
+}
+
+async fn account(State(consumer): State<Consumer>, Path(client): Path<u16>) -> Response {
+    match consumer.ask(|reply| Query::Account { client, reply }).await {
+        Ok(Some(row)) => Json(row).into_response(),
+        Ok(None) => error(StatusCode::NOT_FOUND, format!("unknown client {client}")),
+        Err(response) => response,
+    }
+}
+
+async fn transactions(State(consumer): State<Consumer>, Path(client): Path<u16>) -> Response {
+    match consumer
+        .ask(|reply| Query::Transactions { client, reply })
+        .await
+    {
+        Ok(Ok(Some(entries))) => Json(entries).into_response(),
+        Ok(Ok(None)) => error(StatusCode::NOT_FOUND, format!("unknown client {client}")),
+        Ok(Err(e)) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+        Err(response) => response,
+    }
+}
+
+async fn disputes(State(consumer): State<Consumer>) -> Response {
+    match consumer.ask(Query::Disputes).await {
+        Ok(disputes) => {
+            let disputes: Vec<_> = disputes
+                .into_iter()
+                .map(|(client, tx)| Dispute { client, tx })
+                .collect();
+            Json(disputes).into_response()
+        }
+        Err(response) => response,
+    }
+}
@@ -0,0 +1,618 @@
+use std::{
+    error::Error,
+    fmt,
+    fs::File,
+    io,
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    vec,
+};
+
+use csv::{ErrorKind, Position, ReaderBuilder, StringRecord, Trim};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use serde_json::{value::RawValue, Value};
+use tokio::sync::mpsc::Sender;
+
+#[cfg(feature = "parquet")]
+use crate::columnar::ParquetRows;
+use crate::{
+    validate::validate_fields, RawTransaction, Rejection, TransactionError, TransactionTypes,
+};
+
+/// A row read by a producer task, tagged with its line in the input and, when
+/// read with [`read_source`], the input it came from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Ingested {
+    Record {
+        source: Option<Arc<str>>,
+        line: u64,
+        /// The row's `timestamp` column, if the input has one
+        timestamp: Option<u64>,
+        record: RawTransaction,
+    },
+    Rejected(Rejection),
+}
+
+impl Ingested {
+    /// The source and line the row was read from.
+    pub fn position(&self) -> (Option<&str>, u64) {
+        match self {
+            Ingested::Record { source, line, .. } => (source.as_deref(), *line),
+            Ingested::Rejected(rejection) => (rejection.source.as_deref(), rejection.line),
+        }
+    }
+}
+
+/// Where a producer reads its transactions from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    Stdin,
+    Path(PathBuf),
+}
+
+impl Source {
+    pub fn open(&self) -> io::Result<Box<dyn Read + Send>> {
+        match self {
+            Source::Stdin => Ok(Box::new(io::stdin())),
+            Source::Path(path) => Ok(Box::new(File::open(path)?)),
+        }
+    }
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    /// `-` is stdin, anything else is a file path.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "-" => Ok(Source::Stdin),
+            "" => Err("empty input path".to_string()),
+            _ => Ok(Source::Path(s.into())),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Stdin => f.write_str("-"),
+            Source::Path(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// How records from several sources are fed to the consumer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InputOrdering {
+    /// Every source is read by its own producer at the same time. Records from
+    /// different sources arrive in no particular order.
+    #[default]
+    Interleaved,
+    /// Sources are read one after another, in the order they were given.
+    Sequential,
+}
+
+impl FromStr for InputOrdering {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interleaved" => Ok(InputOrdering::Interleaved),
+            "sequential" => Ok(InputOrdering::Sequential),
+            _ => Err(format!("unknown input ordering '{s}'")),
+        }
+    }
+}
+
+/// The format transactions are read in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InputFormat {
+    /// Judged by the file extension, or failing that by the start of the
+    /// input: `[` is JSON, `{` is JSON Lines, the Parquet magic number is
+    /// Parquet, anything else is CSV.
+    #[default]
+    Auto,
+    Csv,
+    /// A single array of transaction objects
+    Json,
+    /// One transaction object per line
+    JsonLines,
+    /// A Parquet file with the columns of a CSV input
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl InputFormat {
+    /// The format of `path` by its extension, or `Auto` if the extension is
+    /// not a known one.
+    pub fn from_path(path: impl AsRef<Path>) -> InputFormat {
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("csv") => InputFormat::Csv,
+            Some("json") => InputFormat::Json,
+            Some("jsonl" | "ndjson") => InputFormat::JsonLines,
+            #[cfg(feature = "parquet")]
+            Some("parquet") => InputFormat::Parquet,
+            _ => InputFormat::Auto,
+        }
+    }
+
+    fn sniff(start: &[u8]) -> InputFormat {
+        #[cfg(feature = "parquet")]
+        if start.starts_with(b"PAR1") {
+            return InputFormat::Parquet;
+        }
+
+        let start = start.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(start);
+        match start.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'[') => InputFormat::Json,
+            Some(b'{') => InputFormat::JsonLines,
+            _ => InputFormat::Csv,
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(InputFormat::Auto),
+            "csv" => Ok(InputFormat::Csv),
+            "json" => Ok(InputFormat::Json),
+            "jsonl" => Ok(InputFormat::JsonLines),
+            #[cfg(feature = "parquet")]
+            "parquet" => Ok(InputFormat::Parquet),
+            #[cfg(not(feature = "parquet"))]
+            "parquet" => Err("parquet requires building with the 'parquet' feature".to_string()),
+            _ => Err(format!("unknown input format '{s}'")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum IngestError {
+    /// The input could not be read
+    Io(io::Error),
+    /// A row could not be parsed, or was incomplete, while reading in strict mode
+    Malformed {
+        line: u64,
+        byte: u64,
+        reason: String,
+    },
+}
+
+impl fmt::Display for IngestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IngestError::Io(e) => write!(f, "failed to read input: {e}"),
+            IngestError::Malformed { line, byte, reason } => {
+                write!(f, "malformed row at line {line} (byte {byte}): {reason}")
+            }
+        }
+    }
+}
+
+impl Error for IngestError {}
+
+impl From<csv::Error> for IngestError {
+    fn from(e: csv::Error) -> Self {
+        if e.is_io_error() {
+            IngestError::Io(e.into())
+        } else {
+            malformed(&e, e.position())
+        }
+    }
+}
+
+/// Reads CSV transactions from `reader` and sends them down `tx`.
+///
+/// Rows which cannot be parsed (a bad amount, an extra column, etc.) or which
+/// are missing their type, client or tx are sent as [`Ingested::Rejected`] and
+/// reading carries on. In `strict` mode the first such row stops reading and is
+/// returned as an [`IngestError::Malformed`] instead.
+pub async fn read_csv<R: Read>(
+    reader: R,
+    tx: Sender<Ingested>,
+    strict: bool,
+) -> Result<(), IngestError> {
+    read_input(reader, InputFormat::Csv, tx, strict).await
+}
+
+/// Reads transactions in `format` from `reader`, as [`read_csv`] does.
+///
+/// JSON records are the same objects as the `RawTransaction` struct, with an
+/// optional `timestamp`. The records of a JSON array are numbered by their
+/// position in the array rather than by line, and an array which is not valid
+/// JSON as a whole is an error even outside `strict` mode.
+pub async fn read_input<R: Read>(
+    reader: R,
+    format: InputFormat,
+    tx: Sender<Ingested>,
+    strict: bool,
+) -> Result<(), IngestError> {
+    read_tagged(reader, None, format, tx, strict).await
+}
+
+/// Opens `source` and reads it as [`read_input`] does, tagging every record and
+/// rejection with the source it came from. An `Auto` format is judged by the
+/// file extension first. A Parquet file is read from disk a batch at a time
+/// rather than loaded whole.
+pub async fn read_source(
+    source: &Source,
+    format: InputFormat,
+    tx: Sender<Ingested>,
+    strict: bool,
+) -> Result<(), IngestError> {
+    let format = match (format, source) {
+        (InputFormat::Auto, Source::Path(path)) => InputFormat::from_path(path),
+        (format, _) => format,
+    };
+    #[cfg(feature = "parquet")]
+    if let (InputFormat::Parquet, Source::Path(path)) = (format, source) {
+        let file = File::open(path).map_err(IngestError::Io)?;
+        let rows = Rows::<io::Empty>::Parquet(ParquetRows::new(file)?);
+        return read_rows(rows, Some(source.to_string().into()), tx, strict).await;
+    }
+
+    let reader = source.open().map_err(IngestError::Io)?;
+    read_tagged(reader, Some(source.to_string().into()), format, tx, strict).await
+}
+
+async fn read_tagged<R: Read>(
+    reader: R,
+    source: Option<Arc<str>>,
+    format: InputFormat,
+    tx: Sender<Ingested>,
+    strict: bool,
+) -> Result<(), IngestError> {
+    let mut reader = BufReader::new(reader);
+    let format = match format {
+        InputFormat::Auto => InputFormat::sniff(reader.fill_buf().map_err(IngestError::Io)?),
+        format => format,
+    };
+    let rows = Rows::new(reader, format)?;
+    read_rows(rows, source, tx, strict).await
+}
+
+async fn read_rows<R: BufRead>(
+    mut rows: Rows<R>,
+    source: Option<Arc<str>>,
+    tx: Sender<Ingested>,
+    strict: bool,
+) -> Result<(), IngestError> {
+    let tag = |rejection: Rejection| match &source {
+        Some(source) => rejection.with_source(&**source),
+        None => rejection,
+    };
+
+    loop {
+        let ingested = match rows.next() {
+            Ok(None) => break,
+            Ok(Some(Row {
+                line,
+                byte,
+                timestamp,
+                record,
+            })) => {
+                // debug!("{:?}", record);
+
+                match validate_fields(&record) {
+                    Ok(()) => Ingested::Record {
+                        source: source.clone(),
+                        line,
+                        timestamp,
+                        record,
+                    },
+                    Err(e) => {
+                        // warn!("Skipping a bad record: {:?}", record);
+                        if strict {
+                            return Err(IngestError::Malformed {
+                                line,
+                                byte,
+                                reason: e.to_string(),
+                            });
+                        }
+
+                        Ingested::Rejected(tag(Rejection::new(
+                            line,
+                            &record,
+                            TransactionError::Invalid(e),
+                        )))
+                    }
+                }
+            }
+            Err(IngestError::Malformed { line, byte, reason }) if !strict => {
+                Ingested::Rejected(tag(Rejection::malformed(line, byte, reason)))
+            }
+            Err(e) => return Err(e),
+        };
+
+        if tx.send(ingested).await.is_err() {
+            // The consumer has gone away, there is nobody left to read for
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// A record as read, before it is validated.
+pub(crate) struct Row {
+    pub(crate) line: u64,
+    pub(crate) byte: u64,
+    pub(crate) timestamp: Option<u64>,
+    pub(crate) record: RawTransaction,
+}
+
+/// The records of an input, in whichever format it is in.
+enum Rows<R: Read> {
+    Csv {
+        reader: csv::Reader<R>,
+        headers: StringRecord,
+        timestamp_column: Option<usize>,
+        row: StringRecord,
+    },
+    JsonLines {
+        reader: R,
+        buffer: String,
+        line: u64,
+        byte: u64,
+    },
+    Json(vec::IntoIter<Box<RawValue>>, u64),
+    #[cfg(feature = "parquet")]
+    Parquet(ParquetRows),
+}
+
+impl<R: BufRead> Rows<R> {
+    fn new(mut reader: R, format: InputFormat) -> Result<Rows<R>, IngestError> {
+        match format {
+            InputFormat::Auto | InputFormat::Csv => {
+                let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(reader);
+                let headers = reader.headers()?.clone();
+                let timestamp_column = headers.iter().position(|header| header == "timestamp");
+                Ok(Rows::Csv {
+                    reader,
+                    headers,
+                    timestamp_column,
+                    row: StringRecord::new(),
+                })
+            }
+            InputFormat::JsonLines => Ok(Rows::JsonLines {
+                reader,
+                buffer: String::new(),
+                line: 0,
+                byte: 0,
+            }),
+            InputFormat::Json => {
+                let mut input = Vec::new();
+                reader.read_to_end(&mut input).map_err(IngestError::Io)?;
+                let records: Vec<Box<RawValue>> =
+                    serde_json::from_slice(&input).map_err(|e| IngestError::Malformed {
+                        line: e.line() as u64,
+                        byte: 0,
+                        reason: e.to_string(),
+                    })?;
+                Ok(Rows::Json(records.into_iter(), 0))
+            }
+            #[cfg(feature = "parquet")]
+            InputFormat::Parquet => {
+                // Parquet keeps its metadata at the end of the file, so a
+                // reader which cannot seek is read whole. Files are streamed by
+                // `read_source` instead.
+                let mut input = Vec::new();
+                reader.read_to_end(&mut input).map_err(IngestError::Io)?;
+                Ok(Rows::Parquet(ParquetRows::new(bytes::Bytes::from(input))?))
+            }
+        }
+    }
+
+    fn next(&mut self) -> Result<Option<Row>, IngestError> {
+        match self {
+            Rows::Csv {
+                reader,
+                headers,
+                timestamp_column,
+                row,
+            } => read_row(reader, headers, *timestamp_column, row),
+            Rows::JsonLines {
+                reader,
+                buffer,
+                line,
+                byte,
+            } => loop {
+                buffer.clear();
+                let read = reader.read_line(buffer).map_err(IngestError::Io)?;
+                if read == 0 {
+                    return Ok(None);
+                }
+                *line += 1;
+                let position = *byte;
+                *byte += read as u64;
+
+                // Blank lines are skipped
+                let text = buffer.trim();
+                if text.is_empty() {
+                    continue;
+                }
+
+                let malformed = |reason| IngestError::Malformed {
+                    line: *line,
+                    byte: position,
+                    reason,
+                };
+                let (timestamp, record) = parse_json(text.as_bytes()).map_err(malformed)?;
+                return Ok(Some(Row {
+                    line: *line,
+                    byte: position,
+                    timestamp,
+                    record,
+                }));
+            },
+            Rows::Json(records, position) => {
+                let Some(value) = records.next() else {
+                    return Ok(None);
+                };
+                *position += 1;
+
+                let (timestamp, record) = parse_json(value.get().as_bytes()).map_err(|reason| {
+                    IngestError::Malformed {
+                        line: *position,
+                        byte: 0,
+                        reason,
+                    }
+                })?;
+                Ok(Some(Row {
+                    line: *position,
+                    byte: 0,
+                    timestamp,
+                    record,
+                }))
+            }
+            #[cfg(feature = "parquet")]
+            Rows::Parquet(rows) => rows.next(),
+        }
+    }
+}
+
+/// A JSON record, with its amount kept as written so that a number is read
+/// exactly rather than as an f64.
+#[derive(Deserialize)]
+struct JsonRecord<'a> {
+    #[serde(alias = "type")]
+    transaction_type: Option<TransactionTypes>,
+    client: Option<u16>,
+    tx: Option<u32>,
+    #[serde(borrow, default)]
+    amount: Option<&'a RawValue>,
+    #[serde(default)]
+    timestamp: Option<Value>,
+}
+
+/// Parses a JSON record, splitting off its optional `timestamp`. This is the
+/// one place JSON records are read, by every input and by the REST API.
+pub(crate) fn parse_json(text: &[u8]) -> Result<(Option<u64>, RawTransaction), String> {
+    let json: JsonRecord = serde_json::from_slice(text).map_err(|e| e.to_string())?;
+
+    let timestamp = match json.timestamp {
+        None | Some(Value::Null) => None,
+        Some(Value::Number(timestamp)) if timestamp.is_u64() => timestamp.as_u64(),
+        Some(Value::String(timestamp)) if timestamp.is_empty() => None,
+        Some(Value::String(timestamp)) => Some(
+            timestamp
+                .parse()
+                .map_err(|_| format!("invalid timestamp '{timestamp}'"))?,
+        ),
+        Some(timestamp) => return Err(format!("invalid timestamp '{timestamp}'")),
+    };
+
+    let record = RawTransaction {
+        transaction_type: json.transaction_type,
+        client: json.client,
+        tx: json.tx,
+        amount: json.amount.map(exact_amount).transpose()?,
+    };
+    Ok((timestamp, record))
+}
+
+/// Reads an amount given either as a string or as a number, from its text.
+fn exact_amount(amount: &RawValue) -> Result<Decimal, String> {
+    let text = amount.get();
+    if text.starts_with('"') {
+        // A string is read as the Decimal itself reads one
+        return serde_json::from_str(text).map_err(|e| e.to_string());
+    }
+
+    Decimal::from_str(text)
+        .or_else(|_| Decimal::from_scientific(text))
+        .map_err(|_| format!("invalid amount {text}"))
+}
+
+fn read_row<R: Read>(
+    csv_reader: &mut csv::Reader<R>,
+    headers: &StringRecord,
+    timestamp_column: Option<usize>,
+    row: &mut StringRecord,
+) -> Result<Option<Row>, IngestError> {
+    match csv_reader.read_record(row) {
+        Ok(false) => Ok(None),
+        Ok(true) => {
+            let line = row.position().map_or(0, Position::line);
+            let byte = row.position().map_or(0, Position::byte);
+
+            // An empty timestamp is treated as a missing one
+            let timestamp = match timestamp_column.and_then(|i| row.get(i)) {
+                None | Some("") => None,
+                Some(timestamp) => Some(timestamp.parse().map_err(|_| IngestError::Malformed {
+                    line,
+                    byte,
+                    reason: format!("invalid timestamp '{timestamp}'"),
+                })?),
+            };
+
+            row.deserialize(Some(headers))
+                .map(|record| {
+                    Some(Row {
+                        line,
+                        byte,
+                        timestamp,
+                        record,
+                    })
+                })
+                .map_err(|e| malformed(&e, row.position()))
+        }
+        Err(e) if e.is_io_error() => Err(e.into()),
+        Err(e) => Err(malformed(&e, e.position())),
+    }
+}
+
+fn malformed(error: &csv::Error, position: Option<&Position>) -> IngestError {
+    let reason = match error.kind() {
+        ErrorKind::Deserialize { err, .. } => err.to_string(),
+        ErrorKind::UnequalLengths {
+            expected_len, len, ..
+        } => format!("expected {expected_len} fields, found {len}"),
+        _ => error.to_string(),
+    };
+
+    IngestError::Malformed {
+        line: position.map_or(0, Position::line),
+        byte: position.map_or(0, Position::byte),
+        reason,
+    }
+}
+
+/// Parses a single transaction, either as a JSON object or as a CSV row with
+/// the columns `type, client, tx, amount` (the amount may be left off).
+pub(crate) fn parse_line(line: &str) -> Result<RawTransaction, String> {
+    if line.starts_with('{') {
+        return parse_json(line.as_bytes()).map(|(_, record)| record);
+    }
+
+    let headers = StringRecord::from(vec!["type", "client", "tx", "amount"]);
+    let mut csv_reader = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(Trim::All)
+        .from_reader(line.as_bytes());
+
+    let mut row = StringRecord::new();
+    match csv_reader.read_record(&mut row) {
+        Ok(true) if row.len() <= headers.len() => {
+            row.deserialize(Some(&headers))
+                .map_err(|e| match malformed(&e, None) {
+                    IngestError::Malformed { reason, .. } => reason,
+                    IngestError::Io(e) => e.to_string(),
+                })
+        }
+        Ok(true) => Err(format!(
+            "expected {} fields, found {}",
+            headers.len(),
+            row.len()
+        )),
+        Ok(false) => Err("empty line".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
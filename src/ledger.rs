@@ -0,0 +1,491 @@
+use std::{collections::HashMap, error::Error, fmt};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{TransactionError, TransactionTypes};
+
+/// Where a ledger entry is in its dispute lifecycle. An entry starts out as
+/// `Processed`, may be disputed once, and the dispute ends in either `Resolved`
+/// or `ChargedBack`. Both outcomes are final.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisputeState {
+    #[default]
+    Processed,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+impl DisputeState {
+    /// Returns the state reached by applying a record of type `action` to an
+    /// entry in this state, or the reason the transition is not allowed.
+    pub fn transition(self, action: TransactionTypes) -> Result<DisputeState, TransactionError> {
+        match (self, action) {
+            (_, TransactionTypes::Deposit | TransactionTypes::Withdrawal) => {
+                Err(TransactionError::DuplicateTx)
+            }
+            (DisputeState::Processed, TransactionTypes::Dispute) => Ok(DisputeState::Disputed),
+            (DisputeState::Processed, _) => Err(TransactionError::NotDisputed),
+            (DisputeState::Disputed, TransactionTypes::Dispute) => {
+                Err(TransactionError::AlreadyDisputed)
+            }
+            (DisputeState::Disputed, TransactionTypes::Resolve) => Ok(DisputeState::Resolved),
+            (DisputeState::Disputed, TransactionTypes::Chargeback) => Ok(DisputeState::ChargedBack),
+            (DisputeState::Resolved | DisputeState::ChargedBack, _) => {
+                Err(TransactionError::DisputeClosed)
+            }
+        }
+    }
+}
+
+/// The transactions which are kept in the ledger.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    Deposit,
+    Withdrawal,
+}
+
+/// A deposit or withdrawal held in the ledger, along with its dispute state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct LedgerEntry {
+    #[serde(rename = "type")]
+    pub kind: EntryKind,
+    pub client: u16,
+    pub tx: u32,
+    pub amount: Decimal,
+    pub state: DisputeState,
+}
+
+impl LedgerEntry {
+    pub fn new(kind: EntryKind, client: u16, tx: u32, amount: Decimal) -> LedgerEntry {
+        LedgerEntry {
+            kind,
+            client,
+            tx,
+            amount,
+            state: DisputeState::Processed,
+        }
+    }
+}
+
+/// A failure in the backing storage of a [`LedgerStore`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoreError(pub String);
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ledger storage error: {}", self.0)
+    }
+}
+
+impl Error for StoreError {}
+
+/// The entries of a [`LedgerStore`], read as they are iterated.
+pub type Entries<'a> = Box<dyn Iterator<Item = Result<(u32, LedgerEntry), StoreError>> + 'a>;
+
+/// Where the [`crate::Transactor`] keeps every deposit and withdrawal, keyed by
+/// tx id, along with its dispute state.
+pub trait LedgerStore {
+    fn get(&self, tx: u32) -> Result<Option<LedgerEntry>, StoreError>;
+
+    fn contains(&self, tx: u32) -> Result<bool, StoreError> {
+        Ok(self.get(tx)?.is_some())
+    }
+
+    fn insert(&mut self, tx: u32, entry: LedgerEntry) -> Result<(), StoreError>;
+
+    fn set_state(&mut self, tx: u32, state: DisputeState) -> Result<(), StoreError>;
+
+    /// Every entry in the store, in tx id order. Entries are read as the
+    /// iterator advances, so a store on disk is never loaded whole.
+    fn entries(&self) -> Result<Entries<'_>, StoreError>;
+
+    /// Every entry made by `client`, in tx id order. This reads through the
+    /// whole store, so stores which can look entries up by client do so
+    /// instead.
+    fn client_entries(&self, client: u16) -> Result<Entries<'_>, StoreError> {
+        Ok(Box::new(self.entries()?.filter(move |entry| {
+            entry
+                .as_ref()
+                .map_or(true, |(_, entry)| entry.client == client)
+        })))
+    }
+
+    /// Makes everything written so far durable. A no-op for stores which are
+    /// not persistent.
+    fn flush(&mut self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+impl<L: LedgerStore + ?Sized> LedgerStore for Box<L> {
+    fn get(&self, tx: u32) -> Result<Option<LedgerEntry>, StoreError> {
+        (**self).get(tx)
+    }
+
+    fn contains(&self, tx: u32) -> Result<bool, StoreError> {
+        (**self).contains(tx)
+    }
+
+    fn insert(&mut self, tx: u32, entry: LedgerEntry) -> Result<(), StoreError> {
+        (**self).insert(tx, entry)
+    }
+
+    fn set_state(&mut self, tx: u32, state: DisputeState) -> Result<(), StoreError> {
+        (**self).set_state(tx, state)
+    }
+
+    fn entries(&self) -> Result<Entries<'_>, StoreError> {
+        (**self).entries()
+    }
+
+    fn client_entries(&self, client: u16) -> Result<Entries<'_>, StoreError> {
+        (**self).client_entries(client)
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        (**self).flush()
+    }
+}
+
+/// Keeps the whole ledger in a HashMap. This is the default store.
+#[derive(Debug, Default)]
+pub struct MemoryLedger {
+    entries: HashMap<u32, LedgerEntry>,
+    /// The tx ids of every client's entries, in the order they were inserted
+    clients: HashMap<u16, Vec<u32>>,
+}
+
+impl MemoryLedger {
+    /// Removes the entry for `tx`, if there is one.
+    pub(crate) fn remove(&mut self, tx: u32) {
+        if let Some(entry) = self.entries.remove(&tx) {
+            if let Some(txs) = self.clients.get_mut(&entry.client) {
+                txs.retain(|&other| other != tx);
+            }
+        }
+    }
+}
+
+impl LedgerStore for MemoryLedger {
+    fn get(&self, tx: u32) -> Result<Option<LedgerEntry>, StoreError> {
+        Ok(self.entries.get(&tx).copied())
+    }
+
+    fn contains(&self, tx: u32) -> Result<bool, StoreError> {
+        Ok(self.entries.contains_key(&tx))
+    }
+
+    fn insert(&mut self, tx: u32, entry: LedgerEntry) -> Result<(), StoreError> {
+        if self.entries.insert(tx, entry).is_none() {
+            self.clients.entry(entry.client).or_default().push(tx);
+        }
+        Ok(())
+    }
+
+    fn set_state(&mut self, tx: u32, state: DisputeState) -> Result<(), StoreError> {
+        let entry = self
+            .entries
+            .get_mut(&tx)
+            .ok_or_else(|| StoreError(format!("no entry for tx {tx}")))?;
+        entry.state = state;
+        Ok(())
+    }
+
+    fn entries(&self) -> Result<Entries<'_>, StoreError> {
+        let mut txs: Vec<u32> = self.entries.keys().copied().collect();
+        txs.sort_unstable();
+        Ok(Box::new(txs.into_iter().filter_map(|tx| {
+            self.entries.get(&tx).map(|entry| Ok((tx, *entry)))
+        })))
+    }
+
+    fn client_entries(&self, client: u16) -> Result<Entries<'_>, StoreError> {
+        let mut txs = self.clients.get(&client).cloned().unwrap_or_default();
+        txs.sort_unstable();
+        Ok(Box::new(txs.into_iter().filter_map(|tx| {
+            self.entries.get(&tx).map(|entry| Ok((tx, *entry)))
+        })))
+    }
+}
+
+#[cfg(feature = "disk_ledger")]
+pub use disk::DiskLedger;
+
+#[cfg(feature = "disk_ledger")]
+mod disk {
+    use std::{cell::RefCell, path::Path};
+
+    use redb::{Database, ReadableTable, ReadableTableMetadata, TableDefinition, WriteTransaction};
+    use rust_decimal::Decimal;
+
+    use super::{DisputeState, Entries, EntryKind, LedgerEntry, LedgerStore, StoreError};
+
+    const LEDGER: TableDefinition<u32, &[u8]> = TableDefinition::new("ledger");
+
+    /// An index of the ledger by client, with a row per entry.
+    const CLIENTS: TableDefinition<(u16, u32), ()> = TableDefinition::new("clients");
+
+    /// An entry is stored as its type, dispute state, client (little endian)
+    /// and the 16 byte serialized amount.
+    const ENTRY_LEN: usize = 20;
+
+    /// Writes are committed once this many have been made. redb only reuses
+    /// the pages of a ledger after a durable commit, and every commit costs an
+    /// fsync, so committing each write is both slow and grows the file without
+    /// bound.
+    const COMMIT_EVERY: usize = 4096;
+
+    fn store_error(e: impl Into<redb::Error>) -> StoreError {
+        StoreError(e.into().to_string())
+    }
+
+    /// Keeps the ledger in an embedded redb database file, so that only the
+    /// database's page cache is held in memory.
+    ///
+    /// Writes are made in a single write transaction which is committed, and
+    /// made durable, every few thousand writes. [`LedgerStore::flush`] commits
+    /// the rest, as does dropping the ledger, which ignores any error.
+    pub struct DiskLedger {
+        db: Database,
+        /// The write transaction holding the writes since the last commit.
+        /// Reads go through it, so that they see those writes too.
+        pending: RefCell<Option<WriteTransaction>>,
+        writes: usize,
+    }
+
+    impl DiskLedger {
+        /// Opens the database at `path`, creating it if it does not exist. The
+        /// entries of an existing database are kept, see [`DiskLedger::clear`].
+        pub fn open(path: impl AsRef<Path>) -> Result<DiskLedger, StoreError> {
+            let db = Database::create(path).map_err(store_error)?;
+
+            // Create the tables up front so that reads never find them missing
+            let txn = db.begin_write().map_err(store_error)?;
+            {
+                let ledger = txn.open_table(LEDGER).map_err(store_error)?;
+                let mut clients = txn.open_table(CLIENTS).map_err(store_error)?;
+
+                // A ledger written before the index existed is indexed now
+                if clients.is_empty().map_err(store_error)? {
+                    for row in ledger.iter().map_err(store_error)? {
+                        let (tx, bytes) = row.map_err(store_error)?;
+                        let entry = decode(tx.value(), bytes.value())?;
+                        clients
+                            .insert((entry.client, entry.tx), ())
+                            .map_err(store_error)?;
+                    }
+                }
+            }
+            txn.commit().map_err(store_error)?;
+
+            Ok(DiskLedger {
+                db,
+                pending: RefCell::new(None),
+                writes: 0,
+            })
+        }
+
+        /// Whether the ledger holds no entries, as when it was first created.
+        pub fn is_empty(&self) -> Result<bool, StoreError> {
+            Ok(self.entries()?.next().is_none())
+        }
+
+        /// Removes every entry.
+        pub fn clear(&mut self) -> Result<(), StoreError> {
+            self.commit()?;
+
+            let txn = self.db.begin_write().map_err(store_error)?;
+            txn.delete_table(LEDGER).map_err(store_error)?;
+            txn.delete_table(CLIENTS).map_err(store_error)?;
+            txn.open_table(LEDGER).map_err(store_error)?;
+            txn.open_table(CLIENTS).map_err(store_error)?;
+            txn.commit().map_err(store_error)
+        }
+
+        fn commit(&self) -> Result<(), StoreError> {
+            match self.pending.take() {
+                Some(txn) => txn.commit().map_err(store_error),
+                None => Ok(()),
+            }
+        }
+
+        /// Writes the entry for `tx`, and its row in the client index when
+        /// `index` is set, as it need only be for a new entry.
+        fn write(&mut self, tx: u32, entry: &LedgerEntry, index: bool) -> Result<(), StoreError> {
+            let txn = match self.pending.get_mut().take() {
+                Some(txn) => txn,
+                None => {
+                    self.writes = 0;
+                    self.db.begin_write().map_err(store_error)?
+                }
+            };
+
+            let written = put(&txn, tx, entry, index);
+            if written.is_ok() {
+                self.writes += 1;
+                if self.writes >= COMMIT_EVERY {
+                    return txn.commit().map_err(store_error);
+                }
+            }
+
+            // A failed write leaves the earlier ones to be committed later
+            *self.pending.get_mut() = Some(txn);
+            written
+        }
+    }
+
+    impl Drop for DiskLedger {
+        fn drop(&mut self) {
+            // As with a BufWriter, flush is the way to see a failure
+            let _ = self.commit();
+        }
+    }
+
+    fn put(
+        txn: &WriteTransaction,
+        tx: u32,
+        entry: &LedgerEntry,
+        index: bool,
+    ) -> Result<(), StoreError> {
+        if index {
+            let mut clients = txn.open_table(CLIENTS).map_err(store_error)?;
+            clients
+                .insert((entry.client, tx), ())
+                .map_err(store_error)?;
+        }
+        let mut table = txn.open_table(LEDGER).map_err(store_error)?;
+        table
+            .insert(tx, encode(entry).as_slice())
+            .map_err(store_error)?;
+        Ok(())
+    }
+
+    /// The tx ids of the entries made by `client`, from the index.
+    fn client_txs(
+        clients: &impl ReadableTable<(u16, u32), ()>,
+        client: u16,
+    ) -> Result<Vec<u32>, StoreError> {
+        clients
+            .range((client, 0)..=(client, u32::MAX))
+            .map_err(store_error)?
+            .map(|row| Ok(row.map_err(store_error)?.0.value().1))
+            .collect()
+    }
+
+    impl LedgerStore for DiskLedger {
+        fn get(&self, tx: u32) -> Result<Option<LedgerEntry>, StoreError> {
+            if let Some(txn) = &*self.pending.borrow() {
+                let table = txn.open_table(LEDGER).map_err(store_error)?;
+                let value = table.get(tx).map_err(store_error)?;
+                return value.map(|bytes| decode(tx, bytes.value())).transpose();
+            }
+
+            let txn = self.db.begin_read().map_err(store_error)?;
+            let table = txn.open_table(LEDGER).map_err(store_error)?;
+            let value = table.get(tx).map_err(store_error)?;
+
+            value.map(|bytes| decode(tx, bytes.value())).transpose()
+        }
+
+        fn insert(&mut self, tx: u32, entry: LedgerEntry) -> Result<(), StoreError> {
+            self.write(tx, &entry, true)
+        }
+
+        fn set_state(&mut self, tx: u32, state: DisputeState) -> Result<(), StoreError> {
+            let mut entry = self
+                .get(tx)?
+                .ok_or_else(|| StoreError(format!("no entry for tx {tx}")))?;
+            entry.state = state;
+            self.write(tx, &entry, false)
+        }
+
+        fn entries(&self) -> Result<Entries<'_>, StoreError> {
+            // A read transaction only sees committed writes
+            self.commit()?;
+
+            let txn = self.db.begin_read().map_err(store_error)?;
+            let table = txn.open_table(LEDGER).map_err(store_error)?;
+            let rows = table.range::<u32>(..).map_err(store_error)?;
+
+            Ok(Box::new(rows.map(|row| {
+                let (tx, bytes) = row.map_err(store_error)?;
+                let tx = tx.value();
+                Ok((tx, decode(tx, bytes.value())?))
+            })))
+        }
+
+        fn client_entries(&self, client: u16) -> Result<Entries<'_>, StoreError> {
+            // Read through the pending writes rather than commit them
+            let txs = match &*self.pending.borrow() {
+                Some(txn) => client_txs(&txn.open_table(CLIENTS).map_err(store_error)?, client)?,
+                None => {
+                    let txn = self.db.begin_read().map_err(store_error)?;
+                    client_txs(&txn.open_table(CLIENTS).map_err(store_error)?, client)?
+                }
+            };
+
+            Ok(Box::new(txs.into_iter().filter_map(|tx| {
+                self.get(tx)
+                    .map(|entry| entry.map(|entry| (tx, entry)))
+                    .transpose()
+            })))
+        }
+
+        fn flush(&mut self) -> Result<(), StoreError> {
+            self.commit()
+        }
+    }
+
+    fn encode(entry: &LedgerEntry) -> [u8; ENTRY_LEN] {
+        let kind = match entry.kind {
+            EntryKind::Deposit => 0,
+            EntryKind::Withdrawal => 1,
+        };
+        let state = match entry.state {
+            DisputeState::Processed => 0,
+            DisputeState::Disputed => 1,
+            DisputeState::Resolved => 2,
+            DisputeState::ChargedBack => 3,
+        };
+
+        let mut bytes = [0; ENTRY_LEN];
+        bytes[0] = kind;
+        bytes[1] = state;
+        bytes[2..4].copy_from_slice(&entry.client.to_le_bytes());
+        bytes[4..].copy_from_slice(&entry.amount.serialize());
+        bytes
+    }
+
+    fn decode(tx: u32, bytes: &[u8]) -> Result<LedgerEntry, StoreError> {
+        let corrupt = || StoreError(format!("corrupt entry for tx {tx}"));
+        let bytes: &[u8; ENTRY_LEN] = bytes.try_into().map_err(|_| corrupt())?;
+
+        let kind = match bytes[0] {
+            0 => EntryKind::Deposit,
+            1 => EntryKind::Withdrawal,
+            _ => return Err(corrupt()),
+        };
+        let state = match bytes[1] {
+            0 => DisputeState::Processed,
+            1 => DisputeState::Disputed,
+            2 => DisputeState::Resolved,
+            3 => DisputeState::ChargedBack,
+            _ => return Err(corrupt()),
+        };
+        let client = u16::from_le_bytes([bytes[2], bytes[3]]);
+        let mut amount = [0; 16];
+        amount.copy_from_slice(&bytes[4..]);
+
+        Ok(LedgerEntry {
+            kind,
+            client,
+            tx,
+            amount: Decimal::deserialize(amount),
+            state,
+        })
+    }
+}
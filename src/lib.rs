@@ -1,20 +1,68 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fmt,
+    str::FromStr,
+};
 
 // use log::{debug, warn};
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, Debug, Deserialize)]
-pub struct Transaction {
-    #[serde(alias = "type")]
+#[cfg(feature = "parquet")]
+mod columnar;
+mod command;
+#[cfg(feature = "http")]
+mod http;
+mod ingest;
+mod ledger;
+mod merge;
+mod output;
+mod rejects;
+mod server;
+mod shard;
+mod snapshot;
+mod validate;
+mod wal;
+
+pub use command::Command;
+#[cfg(feature = "http")]
+pub use http::router;
+pub use ingest::{
+    read_csv, read_input, read_source, IngestError, Ingested, InputFormat, InputOrdering, Source,
+};
+#[cfg(feature = "disk_ledger")]
+pub use ledger::DiskLedger;
+pub use ledger::{
+    DisputeState, Entries, EntryKind, LedgerEntry, LedgerStore, MemoryLedger, StoreError,
+};
+pub use merge::{merge, DisputeWindow, MergeOrder, Settled};
+pub use output::{write_rows, AccountRow, OutputFormat};
+pub use rejects::{RejectFormat, RejectWriter, Rejection};
+pub use server::{serve, Query};
+pub use shard::{ShardError, ShardedEngine};
+pub use snapshot::{SnapshotError, SNAPSHOT_VERSION};
+pub use validate::{validate, AmountPolicy, Rounding, ValidationError, MAX_SCALE};
+pub use wal::{FsyncPolicy, WalError, WriteAheadLog};
+
+/// A record as read from an input, before its fields have been checked. The
+/// engine works on the [`Command`] made from it.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RawTransaction {
+    #[serde(alias = "type", rename(serialize = "type"))]
     pub transaction_type: Option<TransactionTypes>,
     pub client: Option<u16>,
     pub tx: Option<u32>,
     pub amount: Option<Decimal>,
 }
 
-#[derive(Clone, Copy, Debug, Deserialize)]
+/// The name [`RawTransaction`] had before records were checked into a
+/// [`Command`].
+pub type Transaction = RawTransaction;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all(serialize = "lowercase"))]
 pub enum TransactionTypes {
     #[serde(alias = "deposit")]
     Deposit,
@@ -28,19 +76,214 @@ pub enum TransactionTypes {
     Chargeback,
 }
 
+/// The effect of a record which was accepted by the [`Transactor`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Deposited,
+    Withdrawn,
+    Disputed,
+    Resolved,
+    ChargedBack,
+    Unlocked,
+}
+
+/// The reason a record was rejected by the [`Transactor`]. A rejected record
+/// leaves the client account untouched.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransactionError {
+    /// The record is not a well formed transaction
+    Invalid(ValidationError),
+    /// The client does not have enough available funds for a withdrawal
+    InsufficientFunds,
+    /// The tx id has already been used, by this or any other client
+    DuplicateTx,
+    /// The client has no account
+    UnknownClient,
+    /// The referenced tx does not exist in the ledger
+    UnknownTx,
+    /// The referenced tx belongs to a different client
+    ClientMismatch,
+    /// The referenced tx is already under dispute
+    AlreadyDisputed,
+    /// The referenced tx is not under dispute
+    NotDisputed,
+    /// The referenced tx has already been resolved or charged back
+    DisputeClosed,
+    /// The client's account is frozen and the [`LockPolicy`] refuses the record
+    AccountLocked,
+    /// An unlock was requested for an account which is not frozen
+    NotLocked,
+    /// The [`LedgerStore`] failed to read or write the ledger
+    Storage(String),
+    /// Applying the record would take one of the account's funds beyond what
+    /// a [`Decimal`] can hold
+    ArithmeticOverflow,
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            TransactionError::Invalid(e) => return e.fmt(f),
+            TransactionError::InsufficientFunds => "insufficient available funds",
+            TransactionError::DuplicateTx => "duplicate transaction id",
+            TransactionError::UnknownClient => "unknown client",
+            TransactionError::UnknownTx => "unknown transaction",
+            TransactionError::ClientMismatch => "transaction belongs to another client",
+            TransactionError::AlreadyDisputed => "transaction is already disputed",
+            TransactionError::NotDisputed => "transaction is not disputed",
+            TransactionError::DisputeClosed => "transaction dispute is already closed",
+            TransactionError::AccountLocked => "account is locked",
+            TransactionError::NotLocked => "account is not locked",
+            TransactionError::Storage(e) => return write!(f, "ledger storage failed: {e}"),
+            TransactionError::ArithmeticOverflow => "amount would overflow the account",
+        };
+
+        f.write_str(reason)
+    }
+}
+
+impl Error for TransactionError {}
+
+impl From<ValidationError> for TransactionError {
+    fn from(e: ValidationError) -> Self {
+        TransactionError::Invalid(e)
+    }
+}
+
+impl From<StoreError> for TransactionError {
+    fn from(e: StoreError) -> Self {
+        TransactionError::Storage(e.0)
+    }
+}
+
+/// Which records are refused once an account has been frozen by a chargeback.
+/// Resolves and chargebacks of disputes which are already open are always
+/// allowed so that pending funds can still be released.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LockPolicy {
+    /// Locked accounts can still be transacted on
+    Ignore,
+    /// Deposits and withdrawals on a locked account are rejected
+    #[default]
+    FreezeFunds,
+    /// Deposits, withdrawals and new disputes on a locked account are rejected
+    FreezeAll,
+}
+
+/// How disputes, resolves and chargebacks move funds for the disputed
+/// transaction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DisputePolicy {
+    /// Every disputed transaction is treated as a deposit, regardless of its
+    /// original type. Kept for comparison with earlier runs.
+    Uniform,
+    /// A disputed withdrawal holds the withdrawn funds rather than the client's
+    /// available funds. Resolving it lets the withdrawal stand, charging it back
+    /// returns the funds to the client.
+    #[default]
+    DirectionAware,
+}
+
+/// An administrative unlock of a frozen account.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Unlock {
+    pub by: String,
+    pub reason: String,
+}
+
+/// A client's account. Accounts are only changed by the [`Transactor`] which
+/// owns them, everyone else gets read access through the getters.
 #[derive(Debug)]
 pub struct Account {
+    pub(crate) available: Decimal,
+    pub(crate) held: Decimal,
+    pub(crate) total: Decimal,
+    pub(crate) locked: bool,
+    pub(crate) disputes: BTreeSet<u32>,
+    pub(crate) unlocks: Vec<Unlock>,
+}
+
+impl Account {
+    pub fn available(&self) -> Decimal {
+        self.available
+    }
+
+    pub fn held(&self) -> Decimal {
+        self.held
+    }
+
+    pub fn total(&self) -> Decimal {
+        self.total
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn balance(&self) -> Balance {
+        Balance {
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
+        }
+    }
+
+    /// Every administrative unlock of this account, oldest first.
+    pub fn unlocks(&self) -> &[Unlock] {
+        &self.unlocks
+    }
+
+    /// The ids of the transactions which are currently under dispute, in
+    /// ascending order.
+    pub fn open_disputes(&self) -> Vec<u32> {
+        self.disputes.iter().copied().collect()
+    }
+}
+
+/// A copy of an account's funds at the time it was taken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Balance {
     pub available: Decimal,
     pub held: Decimal,
     pub total: Decimal,
     pub locked: bool,
-    pub transactions: HashMap<u32, Transaction>,
-    pub disputes: HashSet<u32>,
 }
 
-pub struct Transactor {
-    // TODO: remove public access and make getter to pass tests
-    pub client_accounts: HashMap<u16, Account>,
+/// The column accounts are ordered by when they are listed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortKey {
+    #[default]
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(SortKey::Client),
+            "available" => Ok(SortKey::Available),
+            "held" => Ok(SortKey::Held),
+            "total" => Ok(SortKey::Total),
+            "locked" => Ok(SortKey::Locked),
+            _ => Err(format!("unknown sort column '{s}'")),
+        }
+    }
+}
+
+pub struct Transactor<L: LedgerStore = MemoryLedger> {
+    client_accounts: BTreeMap<u16, Account>,
+    /// Every deposit and withdrawal by tx id, across all clients
+    ledger: L,
+    lock_policy: LockPolicy,
+    dispute_policy: DisputePolicy,
+    amount_policy: AmountPolicy,
 }
 
 impl Default for Transactor {
@@ -51,221 +294,430 @@ impl Default for Transactor {
 
 impl Transactor {
     pub fn new() -> Transactor {
-        let client_accounts = HashMap::new();
-        Transactor { client_accounts }
+        Transactor::with_ledger(MemoryLedger::default())
     }
 
-    pub fn process_a_record(&mut self, record: Transaction) {
-        match record.transaction_type.expect("Record is valid") {
-            TransactionTypes::Deposit => {
-                // debug!("Deposit:{:?}", record);
+    /// Whether `record` has the fields its type calls for. Its amount is
+    /// checked against the [`AmountPolicy`] when it is processed.
+    pub fn is_record_valid(record: &RawTransaction) -> bool {
+        validate::validate_fields(record).is_ok()
+    }
 
-                match self
-                    .client_accounts
-                    .get_mut(&record.client.expect("Client exists"))
-                {
+    /// Processes `record` as if its tx were an entry of `owner`, as the router
+    /// of a [`ShardedEngine`] knows it to be on another shard. The record is
+    /// rejected with the error a single transactor would give it.
+    pub(crate) fn process_taken(
+        &mut self,
+        record: RawTransaction,
+        owner: u16,
+    ) -> Result<Outcome, TransactionError> {
+        let command = Command::try_from(record)?;
+        let tx = command.tx();
+
+        // Stands in for the other shard's entry for as long as the record is
+        // processed, it never has an amount to move
+        let entry = LedgerEntry::new(EntryKind::Deposit, owner, tx, Decimal::ZERO);
+        self.ledger.insert(tx, entry)?;
+        let result = self.process(command);
+        self.ledger.remove(tx);
+        result
+    }
+}
+
+impl<L: LedgerStore> Transactor<L> {
+    /// Creates a transactor which keeps its ledger in `ledger`.
+    pub fn with_ledger(ledger: L) -> Transactor<L> {
+        let client_accounts = BTreeMap::new();
+        Transactor {
+            client_accounts,
+            ledger,
+            lock_policy: LockPolicy::default(),
+            dispute_policy: DisputePolicy::default(),
+            amount_policy: AmountPolicy::default(),
+        }
+    }
+
+    pub fn ledger(&self) -> &L {
+        &self.ledger
+    }
+
+    /// Makes every ledger write so far durable.
+    pub fn flush_ledger(&mut self) -> Result<(), StoreError> {
+        self.ledger.flush()
+    }
+
+    /// Moves every account and ledger entry of `other` into this transactor.
+    /// Accounts of `other` replace any with the same client id, so the two are
+    /// expected to hold different clients, as shards do.
+    pub fn absorb<M: LedgerStore>(&mut self, other: Transactor<M>) -> Result<(), StoreError> {
+        for entry in other.ledger.entries()? {
+            let (tx, entry) = entry?;
+            self.ledger.insert(tx, entry)?;
+        }
+        self.client_accounts.extend(other.client_accounts);
+
+        Ok(())
+    }
+
+    pub fn with_lock_policy(mut self, lock_policy: LockPolicy) -> Transactor<L> {
+        self.lock_policy = lock_policy;
+        self
+    }
+
+    pub fn lock_policy(&self) -> LockPolicy {
+        self.lock_policy
+    }
+
+    pub fn with_dispute_policy(mut self, dispute_policy: DisputePolicy) -> Transactor<L> {
+        self.dispute_policy = dispute_policy;
+        self
+    }
+
+    pub fn dispute_policy(&self) -> DisputePolicy {
+        self.dispute_policy
+    }
+
+    pub fn with_amount_policy(mut self, amount_policy: AmountPolicy) -> Transactor<L> {
+        self.amount_policy = amount_policy;
+        self
+    }
+
+    pub fn amount_policy(&self) -> AmountPolicy {
+        self.amount_policy
+    }
+
+    /// Checks `record` into a [`Command`] and processes it.
+    pub fn process_a_record(
+        &mut self,
+        record: RawTransaction,
+    ) -> Result<Outcome, TransactionError> {
+        self.process(Command::try_from(record)?)
+    }
+
+    /// Applies `command` to the accounts. A deposit or withdrawal's amount is
+    /// checked against the [`AmountPolicy`] first.
+    pub fn process(&mut self, command: Command) -> Result<Outcome, TransactionError> {
+        let direction_aware = self.dispute_policy == DisputePolicy::DirectionAware;
+
+        match command {
+            Command::Deposit { client, tx, amount } => {
+                // debug!("Deposit:{:?}", command);
+
+                // The amount may be rounded, the ledger keeps it as it was applied
+                let amount = self.amount_policy.check(amount)?;
+
+                // As for a withdrawal, a locked account is checked first. The
+                // client need not have an account yet.
+                let v = self.client_accounts.get_mut(&client);
+                if v.as_ref().is_some_and(|v| v.locked) && self.lock_policy != LockPolicy::Ignore {
+                    return Err(TransactionError::AccountLocked);
+                }
+
+                // Check here that a duplicate transaction record doesn't exist,
+                // for this or any other client
+                if self.ledger.contains(tx)? {
+                    return Err(TransactionError::DuplicateTx);
+                }
+
+                // A deposit is a credit to the client's asset account, meaning
+                // it should increase the available and total funds of the
+                // client account. The new funds are worked out before anything
+                // is written, so that an overflow leaves the ledger untouched.
+                let (available, total) = match &v {
+                    Some(v) => (add(v.available, amount)?, add(v.total, amount)?),
+                    None => (amount, amount),
+                };
+
+                // The ledger is written first, so that a storage failure leaves
+                // the account untouched
+                let entry = LedgerEntry::new(EntryKind::Deposit, client, tx, amount);
+                self.ledger.insert(tx, entry)?;
+
+                match v {
                     Some(v) => {
-                        // Check here that a duplicate transaction record doesn't exist
-                        if v.transactions.get(&record.tx.expect("Tx exists")).is_none() {
-                            let amount = record.amount.expect("Amount exists");
-
-                            // A deposit is a credit to the client's asset
-                            // account, meaning it should increase the available
-                            // and total funds of the client account
-                            v.available += amount;
-                            v.total += amount;
-                            v.transactions.insert(record.tx.expect("Tx exists"), record);
-                        }
+                        v.available = available;
+                        v.total = total;
                     }
                     None => {
-                        let amount = record.amount.expect("Amount exists");
-                        let mut transaction = HashMap::new();
-                        transaction.insert(record.tx.expect("Tx exists"), record);
-
                         self.client_accounts.insert(
-                            record.client.expect("Client exists"),
+                            client,
                             Account {
-                                available: amount,
+                                available,
                                 held: dec!(0.0),
-                                total: amount,
+                                total,
                                 locked: false,
-                                transactions: transaction,
-                                disputes: HashSet::new(),
+                                disputes: BTreeSet::new(),
+                                unlocks: Vec::new(),
                             },
                         );
                     }
                 }
+
+                Ok(Outcome::Deposited)
             }
-            TransactionTypes::Withdrawal => {
-                // debug!("Withdrawal:{:?}", record);
+            Command::Withdrawal { client, tx, amount } => {
+                // debug!("Withdrawal:{:?}", command);
 
-                if let Some(v) = self
+                let amount = self.amount_policy.check(amount)?;
+                let v = self
                     .client_accounts
-                    .get_mut(&record.client.expect("Client exists"))
-                {
-                    // Check here that a duplicate transaction record doesn't exist
-                    if v.transactions.get(&record.tx.expect("Tx exists")).is_none() {
-                        let amount = record.amount.expect("Amount exists");
-
-                        // If a client does not have sufficient available
-                        // funds the withdrawal should fail and the total amount
-                        // of funds should not change
-                        if v.available >= amount {
-                            // A withdraw is a debit to the client's asset account,
-                            // meaning it should decrease the available and total
-                            // funds of the client account
-                            v.available -= amount;
-                            v.total -= amount;
-                            v.transactions.insert(record.tx.expect("Tx exists"), record);
-                        }
-                    }
+                    .get_mut(&client)
+                    .ok_or(TransactionError::UnknownClient)?;
+
+                if v.locked && self.lock_policy != LockPolicy::Ignore {
+                    return Err(TransactionError::AccountLocked);
                 }
+
+                // Check here that a duplicate transaction record doesn't exist,
+                // for this or any other client
+                if self.ledger.contains(tx)? {
+                    return Err(TransactionError::DuplicateTx);
+                }
+
+                // If a client does not have sufficient available
+                // funds the withdrawal should fail and the total amount
+                // of funds should not change
+                if v.available < amount {
+                    return Err(TransactionError::InsufficientFunds);
+                }
+
+                // A withdraw is a debit to the client's asset account,
+                // meaning it should decrease the available and total
+                // funds of the client account
+                let (available, total) = (sub(v.available, amount)?, sub(v.total, amount)?);
+
+                let entry = LedgerEntry::new(EntryKind::Withdrawal, client, tx, amount);
+                self.ledger.insert(tx, entry)?;
+
+                v.available = available;
+                v.total = total;
+
+                Ok(Outcome::Withdrawn)
             }
-            TransactionTypes::Dispute => {
-                // debug!("Disputing a transaction:{:?}", record);
+            Command::Dispute { client, tx } => {
+                // debug!("Disputing a transaction:{:?}", command);
 
-                if let Some(v) = self
+                let v = self
                     .client_accounts
-                    .get_mut(&record.client.expect("Client exists"))
-                {
-                    // If condition is met, act. What if available is already less than amount, placing new balance < 0 ?
-                    if let Some(local_trans) =
-                        v.transactions.get(&record.tx.expect("Transaction exists"))
-                    {
-                        if !v
-                            .disputes
-                            .contains(&local_trans.tx.expect("Transaction exists"))
-                        {
-                            // debug!(
-                            //     "Did not find duplicate transaction: {}. Adding",
-                            //     local_trans.tx.expect("Transaction exists")
-                            // );
-                            v.disputes
-                                .insert(local_trans.tx.expect("Transaction exists"));
-
-                            let amount = local_trans.amount.expect("Amount is some");
-
-                            // A dispute represents a client's claim that a transaction was erroneous and should be reversed.
-                            // The transaction shouldn't be reversed yet but the associated funds should be held.
-                            // This means that the clients' available funds should decrease by the amount
-                            // disputed, their held funds should increase by the amount disputed, while their total funds should remain the same.
-                            v.available -= amount;
-                            v.held += amount;
-                            // Do we need to add disputes to the ledger ?
-                            // v.transactions.insert(record.tx.expect("Tx exists"), record);
-                        } else {
-                            // warn!(
-                            //     "Disputed transaction already exists: {}. Skipping",
-                            //     local_trans.tx.expect("Transaction exists")
-                            // );
-                        }
-                    }
+                    .get_mut(&client)
+                    .ok_or(TransactionError::UnknownClient)?;
+
+                if v.locked && self.lock_policy == LockPolicy::FreezeAll {
+                    return Err(TransactionError::AccountLocked);
                 }
+
+                // If condition is met, act. What if available is already less than amount, placing new balance < 0 ?
+                let mut local_trans = self.ledger.get(tx)?.ok_or(TransactionError::UnknownTx)?;
+
+                if local_trans.client != client {
+                    return Err(TransactionError::ClientMismatch);
+                }
+
+                local_trans.state = local_trans.state.transition(command.transaction_type())?;
+                let amount = local_trans.amount;
+
+                let (available, held, total) =
+                    if direction_aware && local_trans.kind == EntryKind::Withdrawal {
+                        // A disputed withdrawal claims the funds should never have left the account. The withdrawn
+                        // funds are held pending the outcome, so held and total funds increase by the amount disputed
+                        // while the client's available funds are unchanged.
+                        (v.available, add(v.held, amount)?, add(v.total, amount)?)
+                    } else {
+                        // A dispute represents a client's claim that a transaction was erroneous and should be reversed.
+                        // The transaction shouldn't be reversed yet but the associated funds should be held.
+                        // This means that the clients' available funds should decrease by the amount
+                        // disputed, their held funds should increase by the amount disputed, while their total funds should remain the same.
+                        (sub(v.available, amount)?, add(v.held, amount)?, v.total)
+                    };
+
+                self.ledger.set_state(tx, local_trans.state)?;
+                v.disputes.insert(tx);
+                (v.available, v.held, v.total) = (available, held, total);
+
+                Ok(Outcome::Disputed)
             }
-            TransactionTypes::Resolve => {
-                // debug!("Resolving a dispute:{:?}", record);
+            Command::Resolve { client, tx } => {
+                // debug!("Resolving a dispute:{:?}", command);
 
-                if let Some(v) = self
+                let v = self
                     .client_accounts
-                    .get_mut(&record.client.expect("Client exists"))
-                {
-                    // If condition is met, act. What if available is already less than amount, placing new balance < 0 ?
-                    if let Some(local_trans) =
-                        v.transactions.get(&record.tx.expect("Transaction exists"))
-                    {
-                        if v.disputes
-                            .contains(&local_trans.tx.expect("Transaction exists"))
-                        {
-                            // debug!(
-                            //     "Found matching transaction: {}. Removing",
-                            //     local_trans.tx.expect("Transaction exists")
-                            // );
-
-                            v.disputes
-                                .remove(&local_trans.tx.expect("Transaction exists"));
-
-                            let amount = local_trans.amount.expect("Amount is some");
-
-                            // A resolve represents a resolution to a dispute, releasing the associated held funds. Funds that
-                            // were previously disputed are no longer disputed. This means that the clients held funds should
-                            // decrease by the amount no longer disputed, their available funds should increase by the amount
-                            // no longer disputed, and their total funds should remain the same.
-                            v.available += amount;
-                            v.held -= amount;
-                            // Do we need to add disputes to the ledger ?
-                            // v.transactions.insert(record.tx.expect("Tx exists"), record);
-                        } else {
-                            // warn!(
-                            //     "Resolve transaction does not exist: {}. Skipping",
-                            //     local_trans.tx.expect("Transaction exists")
-                            // );
-                        }
-                    }
+                    .get_mut(&client)
+                    .ok_or(TransactionError::UnknownClient)?;
+
+                // If condition is met, act. What if available is already less than amount, placing new balance < 0 ?
+                let mut local_trans = self.ledger.get(tx)?.ok_or(TransactionError::UnknownTx)?;
+
+                if local_trans.client != client {
+                    return Err(TransactionError::ClientMismatch);
                 }
+
+                local_trans.state = local_trans.state.transition(command.transaction_type())?;
+                let amount = local_trans.amount;
+
+                let (available, held, total) =
+                    if direction_aware && local_trans.kind == EntryKind::Withdrawal {
+                        // Resolving a disputed withdrawal lets the withdrawal stand. The held funds leave the account,
+                        // so held and total funds decrease by the amount no longer disputed.
+                        (v.available, sub(v.held, amount)?, sub(v.total, amount)?)
+                    } else {
+                        // A resolve represents a resolution to a dispute, releasing the associated held funds. Funds that
+                        // were previously disputed are no longer disputed. This means that the clients held funds should
+                        // decrease by the amount no longer disputed, their available funds should increase by the amount
+                        // no longer disputed, and their total funds should remain the same.
+                        (add(v.available, amount)?, sub(v.held, amount)?, v.total)
+                    };
+
+                self.ledger.set_state(tx, local_trans.state)?;
+                v.disputes.remove(&tx);
+                (v.available, v.held, v.total) = (available, held, total);
+
+                Ok(Outcome::Resolved)
             }
-            TransactionTypes::Chargeback => {
-                // debug!("Chargeback:{:?}", record);
+            Command::Chargeback { client, tx } => {
+                // debug!("Chargeback:{:?}", command);
 
-                if let Some(v) = self
+                let v = self
                     .client_accounts
-                    .get_mut(&record.client.expect("Client exists"))
-                {
-                    // If condition is met, act. What if available is already less than amount, placing new balance < 0 ?
-                    if let Some(local_trans) =
-                        v.transactions.get(&record.tx.expect("Transaction exists"))
-                    {
-                        if v.disputes
-                            .contains(&local_trans.tx.expect("Transaction exists"))
-                        {
-                            // debug!(
-                            //     "Found matching transaction: {}. Removing",
-                            //     local_trans.tx.expect("Transaction exists")
-                            // );
-
-                            v.disputes
-                                .remove(&local_trans.tx.expect("Transaction exists"));
-
-                            let amount = local_trans.amount.expect("Amount is some");
-
-                            // A chargeback is the final state of a dispute and represents the client reversing a transaction.
-                            // Funds that were held have now been withdrawn. This means that the clients held funds and total
-                            // funds should decrease by the amount previously disputed. If a chargeback occurs the client's
-                            // account should be immediately frozen.
-
-                            v.total -= amount;
-                            v.held -= amount;
-                            v.locked = true;
-                            // Do we need to add disputes to the ledger ?
-                            // v.transactions.insert(record.tx.expect("Tx exists"), record);
-                        } else {
-                            // warn!(
-                            //     "Chargeback transaction does not exist: {}. Skipping",
-                            //     local_trans.tx.expect("Transaction exists")
-                            // );
-                        }
-                    }
+                    .get_mut(&client)
+                    .ok_or(TransactionError::UnknownClient)?;
+
+                // If condition is met, act. What if available is already less than amount, placing new balance < 0 ?
+                let mut local_trans = self.ledger.get(tx)?.ok_or(TransactionError::UnknownTx)?;
+
+                if local_trans.client != client {
+                    return Err(TransactionError::ClientMismatch);
                 }
+
+                local_trans.state = local_trans.state.transition(command.transaction_type())?;
+                let amount = local_trans.amount;
+
+                let (available, held, total) =
+                    if direction_aware && local_trans.kind == EntryKind::Withdrawal {
+                        // Charging back a withdrawal reverses it. The held funds are returned to the client, so held
+                        // funds decrease and available funds increase by the amount previously disputed.
+                        (add(v.available, amount)?, sub(v.held, amount)?, v.total)
+                    } else {
+                        // A chargeback is the final state of a dispute and represents the client reversing a transaction.
+                        // Funds that were held have now been withdrawn. This means that the clients held funds and total
+                        // funds should decrease by the amount previously disputed. If a chargeback occurs the client's
+                        // account should be immediately frozen.
+                        (v.available, sub(v.held, amount)?, sub(v.total, amount)?)
+                    };
+
+                self.ledger.set_state(tx, local_trans.state)?;
+                v.disputes.remove(&tx);
+                (v.available, v.held, v.total) = (available, held, total);
+                v.locked = true;
+
+                Ok(Outcome::ChargedBack)
             }
         }
     }
 
-    pub fn display_output(&self) {
-        println!("client, available, held, total, locked");
+    /// Unfreezes an account which was locked by a chargeback. The unlock is
+    /// recorded against the account along with who performed it and why.
+    pub fn unlock(
+        &mut self,
+        client: u16,
+        by: impl Into<String>,
+        reason: impl Into<String>,
+    ) -> Result<Outcome, TransactionError> {
+        let v = self
+            .client_accounts
+            .get_mut(&client)
+            .ok_or(TransactionError::UnknownClient)?;
 
-        for client in &self.client_accounts {
-            println!(
-                "{}, {}, {}, {}, {}",
-                client.0,
-                client.1.available.round_dp(4).normalize(),
-                client.1.held.round_dp(4).normalize(),
-                client.1.total.round_dp(4).normalize(),
-                client.1.locked
-            );
+        if !v.locked {
+            return Err(TransactionError::NotLocked);
         }
+
+        v.locked = false;
+        v.unlocks.push(Unlock {
+            by: by.into(),
+            reason: reason.into(),
+        });
+
+        Ok(Outcome::Unlocked)
     }
 
-    pub fn is_record_valid(record: &Transaction) -> bool {
-        record.transaction_type.is_some() && record.client.is_some() && record.tx.is_some()
-        // && record.amount.is_some()
+    pub fn account(&self, client: u16) -> Option<&Account> {
+        self.client_accounts.get(&client)
     }
+
+    /// All accounts in client id order.
+    pub fn accounts(&self) -> impl Iterator<Item = (u16, &Account)> {
+        self.client_accounts
+            .iter()
+            .map(|(client, account)| (*client, account))
+    }
+
+    pub fn balance(&self, client: u16) -> Option<Balance> {
+        self.account(client).map(Account::balance)
+    }
+
+    /// The ledger entry for a deposit or withdrawal made by `client`.
+    pub fn transaction(&self, client: u16, tx: u32) -> Result<Option<LedgerEntry>, StoreError> {
+        Ok(self.ledger.get(tx)?.filter(|entry| entry.client == client))
+    }
+
+    /// Every deposit and withdrawal made by `client`, in tx order, or None if
+    /// the client has no account.
+    pub fn transactions(&self, client: u16) -> Result<Option<Vec<LedgerEntry>>, StoreError> {
+        if self.account(client).is_none() {
+            return Ok(None);
+        }
+
+        let entries = self
+            .ledger
+            .client_entries(client)?
+            .map(|entry| entry.map(|(_, entry)| entry))
+            .collect::<Result<_, _>>()?;
+        Ok(Some(entries))
+    }
+
+    /// The ids of the client's transactions which are currently under dispute,
+    /// in ascending order. Empty if the client has no account.
+    pub fn open_disputes(&self, client: u16) -> Vec<u32> {
+        self.account(client)
+            .map(Account::open_disputes)
+            .unwrap_or_default()
+    }
+
+    /// All accounts ordered by `sort_by`, ascending. Accounts with equal values
+    /// are ordered by client id, so the order is the same on every run.
+    pub fn accounts_sorted_by(&self, sort_by: SortKey) -> Vec<(u16, &Account)> {
+        let mut accounts: Vec<_> = self.accounts().collect();
+
+        match sort_by {
+            SortKey::Client => {}
+            SortKey::Available => accounts.sort_by_key(|(_, account)| account.available),
+            SortKey::Held => accounts.sort_by_key(|(_, account)| account.held),
+            SortKey::Total => accounts.sort_by_key(|(_, account)| account.total),
+            SortKey::Locked => accounts.sort_by_key(|(_, account)| account.locked),
+        }
+
+        accounts
+    }
+}
+
+/// `a + b`, or [`TransactionError::ArithmeticOverflow`] if the sum does not fit
+/// in a [`Decimal`].
+fn add(a: Decimal, b: Decimal) -> Result<Decimal, TransactionError> {
+    exact(a.checked_add(b), a, b)
+}
+
+/// `a - b`, or [`TransactionError::ArithmeticOverflow`] if the difference does
+/// not fit in a [`Decimal`].
+fn sub(a: Decimal, b: Decimal) -> Result<Decimal, TransactionError> {
+    exact(a.checked_sub(b), a, b)
+}
+
+/// The `result` of adding or subtracting `a` and `b`, if it is exact. Close to
+/// its limits a Decimal drops the decimal places it has no room for, which
+/// would lose funds as surely as an overflow.
+fn exact(result: Option<Decimal>, a: Decimal, b: Decimal) -> Result<Decimal, TransactionError> {
+    let scale = a.normalize().scale().max(b.normalize().scale());
+    result
+        .filter(|result| result.scale() >= scale)
+        .ok_or(TransactionError::ArithmeticOverflow)
 }
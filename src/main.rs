@@ -1,81 +1,469 @@
-use std::{env, error::Error, fs::File/*, time::Instant*/};
+use std::{
+    collections::HashSet,
+    env,
+    error::Error,
+    fs,
+    fs::File,
+    io,
+    io::{BufReader, BufWriter},
+    iter, // time::Instant
+};
 
-use csv::{ReaderBuilder, Trim};
 // use log::{debug, trace, warn};
-use tokio::sync::mpsc::channel;
+use tokio::{net::TcpListener, signal, sync::mpsc::channel, task::JoinSet};
 
 use transactions::*;
 
+const USAGE: &str =
+    "usage: transactions [<input.csv|->...] [--listen <address>] [--http <address>] [--input-format auto|csv|json|jsonl] [--ordering interleaved|sequential] [--merge arrival|tx|timestamp] [--dispute-window <records>] [--shards <n>] [--rejects <rejects.csv|rejects.jsonl|rejects.ndjson>] [--strict] [--amount-scale <places>] [--round-amounts] [--max-amount <amount>] [--sort <column>] [--format csv|json|jsonl|table] [--ledger <ledger.redb>] [--resume-from <snapshot.json>] [--save-snapshot <snapshot.json>] [--wal <wal.jsonl>] [--fsync always|never|<records>]";
+
+struct Args {
+    inputs: Vec<Source>,
+    listen: Option<String>,
+    http: Option<String>,
+    input_format: InputFormat,
+    ordering: InputOrdering,
+    merge: MergeOrder,
+    dispute_window: u64,
+    shards: usize,
+    rejects: Option<String>,
+    strict: bool,
+    amount_policy: AmountPolicy,
+    sort_by: SortKey,
+    format: OutputFormat,
+    ledger: Option<String>,
+    resume_from: Option<String>,
+    save_snapshot: Option<String>,
+    wal: Option<String>,
+    fsync: FsyncPolicy,
+}
+
+impl Args {
+    fn parse() -> Result<Args, Box<dyn Error>> {
+        let mut inputs = Vec::new();
+        let mut listen = None;
+        let mut http = None;
+        let mut input_format = InputFormat::default();
+        let mut ordering = InputOrdering::default();
+        let mut merge = MergeOrder::default();
+        let mut dispute_window = 0;
+        let mut shards = 1;
+        let mut rejects = None;
+        let mut strict = false;
+        let mut amount_policy = AmountPolicy::default();
+        let mut sort_by = SortKey::default();
+        let mut format = OutputFormat::default();
+        let mut ledger = None;
+        let mut resume_from = None;
+        let mut save_snapshot = None;
+        let mut wal = None;
+        let mut fsync = FsyncPolicy::default();
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--listen" => listen = Some(args.next().ok_or(USAGE)?),
+                "--http" => http = Some(args.next().ok_or(USAGE)?),
+                "--input-format" => input_format = args.next().ok_or(USAGE)?.parse()?,
+                "--ordering" => ordering = args.next().ok_or(USAGE)?.parse()?,
+                "--merge" => merge = args.next().ok_or(USAGE)?.parse()?,
+                "--dispute-window" => dispute_window = args.next().ok_or(USAGE)?.parse()?,
+                "--shards" => shards = args.next().ok_or(USAGE)?.parse()?,
+                "--rejects" => rejects = Some(args.next().ok_or(USAGE)?),
+                "--strict" => strict = true,
+                "--amount-scale" => amount_policy.scale = args.next().ok_or(USAGE)?.parse()?,
+                "--round-amounts" => amount_policy.rounding = Rounding::Round,
+                "--max-amount" => {
+                    amount_policy.max_amount = Some(args.next().ok_or(USAGE)?.parse()?)
+                }
+                "--sort" => sort_by = args.next().ok_or(USAGE)?.parse()?,
+                "--format" => format = args.next().ok_or(USAGE)?.parse()?,
+                "--ledger" => ledger = Some(args.next().ok_or(USAGE)?),
+                "--resume-from" => resume_from = Some(args.next().ok_or(USAGE)?),
+                "--save-snapshot" => save_snapshot = Some(args.next().ok_or(USAGE)?),
+                "--wal" => wal = Some(args.next().ok_or(USAGE)?),
+                "--fsync" => fsync = args.next().ok_or(USAGE)?.parse()?,
+                _ if arg.starts_with("--") => {
+                    return Err(format!("unexpected argument '{arg}'\n{USAGE}").into())
+                }
+                _ => inputs.push(arg.parse()?),
+            }
+        }
+
+        if inputs.is_empty() && listen.is_none() && http.is_none() {
+            return Err(USAGE.into());
+        }
+        if inputs
+            .iter()
+            .filter(|input| **input == Source::Stdin)
+            .count()
+            > 1
+        {
+            return Err("stdin ('-') can only be read once".into());
+        }
+
+        if merge != MergeOrder::Arrival && ordering == InputOrdering::Sequential {
+            return Err(
+                "--merge reads every input at once, it cannot be used with --ordering sequential"
+                    .into(),
+            );
+        }
+
+        if wal.is_some() && ledger.is_some() {
+            // The ledger file already holds the records the log would replay
+            return Err("--wal cannot be combined with --ledger".into());
+        }
+
+        if shards == 0 {
+            return Err("--shards must be at least 1".into());
+        }
+        if shards > 1
+            && (wal.is_some()
+                || ledger.is_some()
+                || resume_from.is_some()
+                || dispute_window > 0
+                || listen.is_some()
+                || http.is_some())
+        {
+            return Err(
+                "--shards cannot be combined with --wal, --ledger, --resume-from, --dispute-window, --listen or --http"
+                    .into(),
+            );
+        }
+
+        Ok(Args {
+            inputs,
+            listen,
+            http,
+            input_format,
+            ordering,
+            merge,
+            dispute_window,
+            shards,
+            rejects,
+            strict,
+            amount_policy,
+            sort_by,
+            format,
+            ledger,
+            resume_from,
+            save_snapshot,
+            wal,
+            fsync,
+        })
+    }
+}
+
+/// Logs a record to the write-ahead log, along with its position, and writes it
+/// to the rejects file if it was rejected.
+fn record_outcome(
+    settled: Settled,
+    wal: &mut Option<WriteAheadLog>,
+    rejects: &mut Option<RejectWriter<File>>,
+) -> io::Result<()> {
+    if let Some(wal) = wal {
+        let accepted = settled.result.as_ref().ok().map(|_| &settled.record);
+        wal.append(settled.source.as_deref(), settled.line, accepted)?;
+    }
+
+    if let (Err(e), Some(writer)) = (settled.result, rejects) {
+        let rejection = Rejection::new(settled.line, &settled.record, e);
+        writer.write(&match settled.source {
+            Some(source) => rejection.with_source(&*source),
+            None => rejection,
+        })?;
+    }
+
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<String> = env::args().collect();
-    let input_file = &args[1];
-    // debug!("{:?}", input_file);
+    let args = Args::parse()?;
+    // debug!("{:?}", args.inputs);
 
-    let file = File::open(input_file)?;
+    let mut rejects = match &args.rejects {
+        Some(path) => Some(RejectWriter::new(
+            RejectFormat::from_path(path)?,
+            File::create(path)?,
+        )),
+        None => None,
+    };
 
-    let mut transaction_processor = Transactor::new();
+    let ledger: Box<dyn LedgerStore> = match &args.ledger {
+        #[cfg(feature = "disk_ledger")]
+        Some(path) => {
+            let mut ledger = DiskLedger::open(path)?;
+            if args.resume_from.is_some() {
+                // The snapshot holds the whole ledger, whatever the file has
+                // from later runs is rebuilt from it
+                ledger.clear()?;
+            } else if !ledger.is_empty()? {
+                return Err(format!(
+                    "the ledger {path} holds entries from an earlier run, resume from that run's snapshot with --resume-from or use a new ledger file"
+                )
+                .into());
+            }
+            Box::new(ledger)
+        }
+        #[cfg(not(feature = "disk_ledger"))]
+        Some(_) => return Err("--ledger requires building with the 'disk_ledger' feature".into()),
+        None => Box::new(MemoryLedger::default()),
+    };
+    let mut transaction_processor =
+        Transactor::with_ledger(ledger).with_amount_policy(args.amount_policy);
+
+    // The write-ahead log entries the snapshot already holds
+    let mut wal_seq = 0;
+    if let Some(path) = &args.resume_from {
+        wal_seq = transaction_processor.restore_snapshot(BufReader::new(File::open(path)?))?;
+    }
+
+    // Records accepted before a crash are replayed on top of the snapshot, if any
+    let mut wal = match &args.wal {
+        Some(path) => {
+            Some(WriteAheadLog::recover(path, args.fsync, &mut transaction_processor, wal_seq)?.0)
+        }
+        None => None,
+    };
 
     // let start_time = Instant::now();
 
+    // The inputs can be read again after a crash, which the lines already in the
+    // write-ahead log are skipped for
+    let inputs: HashSet<String> = args.inputs.iter().map(ToString::to_string).collect();
+
     let (tx, mut rx) = channel(32);
+    let mut producers = JoinSet::new();
 
-    // Note to tester:
-    // To use multiple input-processing threads simply build with the feature 'additional_task'
-    // cargo build --features=additional_task
-    // This will create an additional async thread to process an additional input file.
-    // NOTE: FILE NAME BELOW IS HARDCODED AND MUST BE PRESENT.
-    #[cfg(feature = "additional_task")]
-    tokio::spawn({
+    match (args.merge, args.ordering) {
+        (MergeOrder::Arrival, InputOrdering::Interleaved) => {
+            for source in args.inputs {
+                let tx = tx.clone();
+                producers.spawn(async move {
+                    read_source(&source, args.input_format, tx, args.strict)
+                        .await
+                        .map_err(|e| format!("{source}: {e}"))
+                });
+            }
+        }
+        (MergeOrder::Arrival, InputOrdering::Sequential) => {
+            let tx = tx.clone();
+            producers.spawn(async move {
+                for source in args.inputs {
+                    read_source(&source, args.input_format, tx.clone(), args.strict)
+                        .await
+                        .map_err(|e| format!("{source}: {e}"))?;
+                }
+                Ok(())
+            });
+        }
+        (order, _) => {
+            // Every source gets a channel of its own, which the merge stage reads
+            // from in turn
+            let mut inputs = Vec::new();
+            for source in args.inputs {
+                let (source_tx, source_rx) = channel(32);
+                inputs.push(source_rx);
+                producers.spawn(async move {
+                    read_source(&source, args.input_format, source_tx, args.strict)
+                        .await
+                        .map_err(|e| format!("{source}: {e}"))
+                });
+            }
+
+            let tx = tx.clone();
+            producers.spawn(async move {
+                merge(inputs, order, tx).await;
+                Ok(())
+            });
+        }
+    }
+    // Queries are only ever sent by the servers
+    let (query_tx, mut queries) = channel(32);
+    if let Some(address) = &args.listen {
+        let listener = TcpListener::bind(address).await?;
         let tx = tx.clone();
-        async move {
-            // !!!ENSURE THE FILE EXISTS!!!
-            let file = File::open("input_data/test_data_2.csv");
-            let file = file.unwrap();
-            let mut csv_reader = ReaderBuilder::new().trim(Trim::All).from_reader(file);
-
-            for result in csv_reader.deserialize() {
-                let record: Transaction = result.unwrap();
-                // debug!("{:?}", record);
-
-                if Transactor::is_record_valid(&record) {
-                    tx.send(record).await.unwrap();
-                } else {
-                    // warn!("Skipping a bad record: {:?}", record);
+        let query_tx = query_tx.clone();
+        producers.spawn(async move {
+            // Ctrl-C stops the server, the report is written once the consumer is done
+            let shutdown = async {
+                let _ = signal::ctrl_c().await;
+            };
+            serve(listener, tx, query_tx, shutdown)
+                .await
+                .map_err(|e| format!("server: {e}"))
+        });
+    }
+    match &args.http {
+        #[cfg(feature = "http")]
+        Some(address) => {
+            let listener = TcpListener::bind(address).await?;
+            let app = router(query_tx.clone());
+            producers.spawn(async move {
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(async {
+                        let _ = signal::ctrl_c().await;
+                    })
+                    .await
+                    .map_err(|e| format!("http server: {e}"))
+            });
+        }
+        #[cfg(not(feature = "http"))]
+        Some(_) => return Err("--http requires building with the 'http' feature".into()),
+        None => {}
+    }
+    drop(query_tx);
+
+    // Only the producers hold a sender now, so the channel closes once they are done
+    drop(tx);
+
+    // let mut record_count = 0;
+
+    if args.shards > 1 {
+        let (mut engine, mut rejected) = ShardedEngine::spawn(args.shards, || {
+            Transactor::new()
+                .with_lock_policy(transaction_processor.lock_policy())
+                .with_dispute_policy(transaction_processor.dispute_policy())
+                .with_amount_policy(transaction_processor.amount_policy())
+        });
+
+        loop {
+            // A producer which fails, as in strict mode, stops the run straight away
+            let received = tokio::select! {
+                biased;
+                Some(producer) = producers.join_next() => {
+                    producer??;
+                    continue;
+                }
+                Some(received) = rx.recv() => received,
+                else => break,
+            };
+
+            let rejection = match received {
+                Ingested::Record {
+                    source,
+                    line,
+                    record,
+                    ..
+                } => engine.process(source, line, record).await,
+                Ingested::Rejected(rejection) => {
+                    if let Some(writer) = &mut rejects {
+                        writer.write(&rejection)?;
+                    }
+                    None
                 }
+            };
+
+            for settled in rejection
+                .into_iter()
+                .chain(iter::from_fn(|| rejected.try_recv().ok()))
+            {
+                record_outcome(settled, &mut wal, &mut rejects)?;
             }
+        }
 
-            // debug!("Closing thread 2");
+        engine.finish(&mut transaction_processor).await?;
+        while let Some(settled) = rejected.recv().await {
+            record_outcome(settled, &mut wal, &mut rejects)?;
         }
-    });
+    } else {
+        let mut dispute_window = DisputeWindow::new(args.dispute_window);
 
-    tokio::spawn({
-        async move {
-            let mut csv_reader = ReaderBuilder::new().trim(Trim::All).from_reader(file);
+        loop {
+            // Records are preferred, so a query is only answered once the records
+            // queued ahead of it have been processed. The consumer runs until
+            // neither records nor queries can arrive any more, or a producer
+            // fails.
+            let received = tokio::select! {
+                biased;
+                // A producer which fails, as in strict mode, stops the run
+                // straight away
+                Some(producer) = producers.join_next() => {
+                    producer??;
+                    continue;
+                }
+                Some(received) = rx.recv() => received,
+                Some(query) = queries.recv() => {
+                    if let Some(settled) = query.answer(&mut transaction_processor) {
+                        record_outcome(settled, &mut wal, &mut rejects)?;
+                    }
+                    continue;
+                }
+                else => break,
+            };
 
-            for result in csv_reader.deserialize() {
-                let record: Transaction = result.unwrap();
-                // debug!("{:?}", record);
+            if let (Some(wal), (Some(source), line)) = (&wal, received.position()) {
+                if inputs.contains(source) && wal.is_logged(source, line) {
+                    continue;
+                }
+            }
 
-                if Transactor::is_record_valid(&record) {
-                    tx.send(record).await.unwrap();
-                } else {
-                    // warn!("Skipping a bad record: {:?}", record);
+            // record_count += 1;
+            match received {
+                Ingested::Record {
+                    source,
+                    line,
+                    record,
+                    ..
+                } => {
+                    for settled in
+                        dispute_window.process(&mut transaction_processor, source, line, record)
+                    {
+                        record_outcome(settled, &mut wal, &mut rejects)?;
+                    }
+                }
+                Ingested::Rejected(rejection) => {
+                    if let Some(wal) = &mut wal {
+                        wal.append(rejection.source.as_deref(), rejection.line, None)?;
+                    }
+                    if let Some(writer) = &mut rejects {
+                        writer.write(&rejection)?;
+                    }
                 }
             }
+        }
 
-            // debug!("Closing thread 1");
+        // Anything still held back never found the record it was waiting for
+        for settled in dispute_window.finish() {
+            record_outcome(settled, &mut wal, &mut rejects)?;
         }
-    });
+    }
 
-    // let mut record_count = 0;
+    if let Some(writer) = &mut rejects {
+        writer.flush()?;
+    }
+
+    while let Some(producer) = producers.join_next().await {
+        producer??;
+    }
 
-    while let Some(received) = rx.recv().await {
-        // record_count += 1;
-        transaction_processor.process_a_record(received);
+    transaction_processor.flush_ledger()?;
+    if let Some(wal) = &mut wal {
+        wal.sync()?;
+    }
+
+    if let Some(path) = &args.save_snapshot {
+        // Written alongside and renamed into place, so that a failed run never
+        // leaves a partial snapshot behind
+        let partial = format!("{path}.partial");
+        let wal_seq = wal.as_ref().map_or(wal_seq, WriteAheadLog::seq);
+        transaction_processor.save_snapshot_at(BufWriter::new(File::create(&partial)?), wal_seq)?;
+        fs::rename(&partial, path)?;
+
+        // The snapshot holds everything the log would replay. Should the run
+        // crash before the log is emptied, recovering from the snapshot skips
+        // the entries up to the one it was saved at.
+        if let Some(wal) = &mut wal {
+            wal.reset()?;
+        }
     }
 
-    transaction_processor.display_output();
+    transaction_processor.write_accounts_sorted_by(
+        args.format,
+        args.sort_by,
+        io::stdout().lock(),
+    )?;
 
     // let duration = start_time.elapsed();
     // trace!("Processed {} records in {:?}", record_count, duration);
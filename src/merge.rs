@@ -0,0 +1,312 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    str::FromStr,
+    sync::Arc,
+};
+
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use crate::{
+    Ingested, LedgerStore, Outcome, RawTransaction, TransactionError, TransactionTypes, Transactor,
+};
+
+/// The order records from several sources are handed to the consumer in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MergeOrder {
+    /// Whichever record arrives first, with no ordering across sources
+    #[default]
+    Arrival,
+    /// Ascending tx id. A dispute, resolve or chargeback sorts after the
+    /// deposit or withdrawal it refers to.
+    Tx,
+    /// Ascending `timestamp` column. Records without a timestamp sort first.
+    Timestamp,
+}
+
+impl FromStr for MergeOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "arrival" => Ok(MergeOrder::Arrival),
+            "tx" => Ok(MergeOrder::Tx),
+            "timestamp" => Ok(MergeOrder::Timestamp),
+            _ => Err(format!("unknown merge order '{s}'")),
+        }
+    }
+}
+
+/// Merges the records of several sources into `tx`, in `order`.
+///
+/// This is a k-way merge: each source is expected to already be in `order`,
+/// and the next record sent is always the smallest one at the head of any
+/// source. Ties go to the source which comes first in `inputs`, so the output
+/// is the same on every run. Rejected rows are passed on as soon as they reach
+/// the head of their source.
+pub async fn merge(mut inputs: Vec<Receiver<Ingested>>, order: MergeOrder, tx: Sender<Ingested>) {
+    let mut heads = Vec::with_capacity(inputs.len());
+    for input in &mut inputs {
+        heads.push(input.recv().await);
+    }
+
+    loop {
+        let next = heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, head)| head.as_ref().map(|head| (merge_key(head, order), i)))
+            .min();
+        let Some((_, i)) = next else {
+            // Every source is exhausted
+            break;
+        };
+
+        let refill = inputs[i].recv().await;
+        let Some(ingested) = std::mem::replace(&mut heads[i], refill) else {
+            break;
+        };
+
+        if tx.send(ingested).await.is_err() {
+            // The consumer has gone away
+            break;
+        }
+    }
+}
+
+fn merge_key(ingested: &Ingested, order: MergeOrder) -> (bool, u64, u8) {
+    let Ingested::Record {
+        timestamp, record, ..
+    } = ingested
+    else {
+        return (false, 0, 0);
+    };
+
+    let primary = match order {
+        MergeOrder::Arrival => 0,
+        MergeOrder::Tx => record.tx.map_or(0, u64::from),
+        MergeOrder::Timestamp => timestamp.unwrap_or(0),
+    };
+    // Among records with the same key, the deposit or withdrawal comes before
+    // the dispute, which comes before its resolve or chargeback
+    let rank = match record.transaction_type {
+        Some(TransactionTypes::Deposit | TransactionTypes::Withdrawal) | None => 0,
+        Some(TransactionTypes::Dispute) => 1,
+        Some(TransactionTypes::Resolve | TransactionTypes::Chargeback) => 2,
+    };
+
+    (true, primary, rank)
+}
+
+/// A record which has been processed by a [`DisputeWindow`], along with the
+/// outcome.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settled {
+    pub source: Option<Arc<str>>,
+    pub line: u64,
+    pub record: RawTransaction,
+    pub result: Result<Outcome, TransactionError>,
+}
+
+struct Parked {
+    id: u64,
+    source: Option<Arc<str>>,
+    line: u64,
+    record: RawTransaction,
+    error: TransactionError,
+}
+
+/// Holds back disputes, resolves and chargebacks which arrive before the
+/// transaction they refer to, or before the dispute they settle, and retries
+/// them once it has been processed.
+///
+/// A held record is given `window` further records to become valid. After that
+/// it is settled with the error it was last rejected with.
+pub struct DisputeWindow {
+    window: u64,
+    processed: u64,
+    next_id: u64,
+    /// Held records by the tx they refer to, oldest first
+    parked: HashMap<u32, VecDeque<Parked>>,
+    /// The tx and id of every held record, in the order they expire
+    expiry: VecDeque<(u64, u32, u64)>,
+}
+
+impl DisputeWindow {
+    /// A window of 0 holds nothing back, every record is settled immediately.
+    pub fn new(window: u64) -> DisputeWindow {
+        DisputeWindow {
+            window,
+            processed: 0,
+            next_id: 0,
+            parked: HashMap::new(),
+            expiry: VecDeque::new(),
+        }
+    }
+
+    /// The number of records currently held back.
+    pub fn held(&self) -> usize {
+        self.parked.values().map(VecDeque::len).sum()
+    }
+
+    /// Processes `record` and returns every record which was settled by it: the
+    /// record itself unless it was held back, any held records it made valid,
+    /// and any held records whose window ran out.
+    pub fn process<L: LedgerStore>(
+        &mut self,
+        transactor: &mut Transactor<L>,
+        source: Option<Arc<str>>,
+        line: u64,
+        record: RawTransaction,
+    ) -> Vec<Settled> {
+        let mut settled = Vec::new();
+        self.processed += 1;
+
+        let result = transactor.process_a_record(record);
+        match (&result, record.tx) {
+            (Ok(_), Some(tx)) => {
+                settled.push(Settled {
+                    source,
+                    line,
+                    record,
+                    result,
+                });
+                self.retry(transactor, tx, &mut settled);
+            }
+            (Err(error), Some(tx)) if self.window > 0 && is_early(&record, error) => {
+                self.park(tx, source, line, record, error.clone());
+            }
+            _ => settled.push(Settled {
+                source,
+                line,
+                record,
+                result,
+            }),
+        }
+
+        self.expire(false, &mut settled);
+        settled
+    }
+
+    /// Settles every record which is still held back, with the error it was
+    /// last rejected with. Called once the input is exhausted.
+    pub fn finish(&mut self) -> Vec<Settled> {
+        let mut settled = Vec::new();
+        self.expire(true, &mut settled);
+        settled
+    }
+
+    fn park(
+        &mut self,
+        tx: u32,
+        source: Option<Arc<str>>,
+        line: u64,
+        record: RawTransaction,
+        error: TransactionError,
+    ) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let expires_at = self.processed.saturating_add(self.window);
+
+        self.expiry.push_back((expires_at, tx, id));
+        self.parked.entry(tx).or_default().push_back(Parked {
+            id,
+            source,
+            line,
+            record,
+            error,
+        });
+    }
+
+    /// Retries the records held for `tx`, oldest first, until none of them
+    /// can make progress. Each one which is accepted may make another valid
+    /// (a dispute, then its resolve).
+    fn retry<L: LedgerStore>(
+        &mut self,
+        transactor: &mut Transactor<L>,
+        tx: u32,
+        settled: &mut Vec<Settled>,
+    ) {
+        let Some(mut held) = self.parked.remove(&tx) else {
+            return;
+        };
+
+        loop {
+            let mut progress = false;
+            let mut still_held = VecDeque::new();
+
+            for mut parked in held {
+                match transactor.process_a_record(parked.record) {
+                    Err(error) if is_early(&parked.record, &error) => {
+                        // Keep the latest reason, it is the one reported if the window runs out
+                        parked.error = error;
+                        still_held.push_back(parked);
+                    }
+                    result => {
+                        progress |= result.is_ok();
+                        settled.push(Settled {
+                            source: parked.source,
+                            line: parked.line,
+                            record: parked.record,
+                            result,
+                        });
+                    }
+                }
+            }
+
+            held = still_held;
+            if !progress || held.is_empty() {
+                break;
+            }
+        }
+
+        if !held.is_empty() {
+            self.parked.insert(tx, held);
+        }
+    }
+
+    /// Settles the held records whose window has run out, or every held record
+    /// if `everything` is set.
+    fn expire(&mut self, everything: bool, settled: &mut Vec<Settled>) {
+        while let Some(&(expires_at, tx, id)) = self.expiry.front() {
+            if !everything && expires_at > self.processed {
+                break;
+            }
+            self.expiry.pop_front();
+
+            // The record may have been settled by a retry since it was held
+            let Some(held) = self.parked.get_mut(&tx) else {
+                continue;
+            };
+            let Some(position) = held.iter().position(|parked| parked.id == id) else {
+                continue;
+            };
+            if let Some(parked) = held.remove(position) {
+                settled.push(Settled {
+                    source: parked.source,
+                    line: parked.line,
+                    record: parked.record,
+                    result: Err(parked.error),
+                });
+            }
+            if held.is_empty() {
+                self.parked.remove(&tx);
+            }
+        }
+    }
+}
+
+/// Whether `error` may only mean that `record` arrived ahead of the record it
+/// depends on.
+fn is_early(record: &RawTransaction, error: &TransactionError) -> bool {
+    let settles_dispute = matches!(
+        record.transaction_type,
+        Some(TransactionTypes::Dispute | TransactionTypes::Resolve | TransactionTypes::Chargeback)
+    );
+
+    settles_dispute
+        && matches!(
+            error,
+            TransactionError::UnknownTx
+                | TransactionError::UnknownClient
+                | TransactionError::NotDisputed
+        )
+}
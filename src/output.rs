@@ -0,0 +1,172 @@
+use std::{io, io::Write, str::FromStr};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{Account, LedgerStore, SortKey, Transactor};
+
+const HEADER: [&str; 5] = ["client", "available", "held", "total", "locked"];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Json,
+    JsonLines,
+    /// A human readable, aligned table
+    Table,
+    /// A Parquet file, with amounts as decimals
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::JsonLines),
+            "table" => Ok(OutputFormat::Table),
+            #[cfg(feature = "parquet")]
+            "parquet" => Ok(OutputFormat::Parquet),
+            #[cfg(not(feature = "parquet"))]
+            "parquet" => Err("parquet requires building with the 'parquet' feature".to_string()),
+            _ => Err(format!("unknown output format '{s}'")),
+        }
+    }
+}
+
+/// One line of the account report. Amounts are rounded to four decimal places.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct AccountRow {
+    pub client: u16,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl AccountRow {
+    pub fn new(client: u16, account: &Account) -> AccountRow {
+        AccountRow {
+            client,
+            available: account.available.round_dp(4).normalize(),
+            held: account.held.round_dp(4).normalize(),
+            total: account.total.round_dp(4).normalize(),
+            locked: account.locked,
+        }
+    }
+}
+
+impl<L: LedgerStore> Transactor<L> {
+    /// Writes every account, in client id order, to `w`.
+    pub fn write_accounts<W: Write>(&self, format: OutputFormat, w: W) -> io::Result<()> {
+        self.write_accounts_sorted_by(format, SortKey::Client, w)
+    }
+
+    pub fn write_accounts_sorted_by<W: Write>(
+        &self,
+        format: OutputFormat,
+        sort_by: SortKey,
+        w: W,
+    ) -> io::Result<()> {
+        let rows = self
+            .accounts_sorted_by(sort_by)
+            .into_iter()
+            .map(|(client, account)| AccountRow::new(client, account));
+
+        write_rows(format, rows, w)
+    }
+
+    /// Writes the account report as CSV to stdout.
+    pub fn display_output(&self) -> io::Result<()> {
+        self.write_accounts(OutputFormat::Csv, io::stdout().lock())
+    }
+}
+
+/// Writes `rows` to `w` in the given format.
+pub fn write_rows<W, I>(format: OutputFormat, rows: I, mut w: W) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = AccountRow>,
+{
+    match format {
+        OutputFormat::Csv => {
+            // The header is written by hand so that an empty report still has one
+            let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(w);
+            writer.write_record(HEADER)?;
+            for row in rows {
+                writer.serialize(row)?;
+            }
+            writer.flush()?;
+        }
+        OutputFormat::Json => {
+            let rows: Vec<_> = rows.into_iter().collect();
+            serde_json::to_writer_pretty(&mut w, &rows)?;
+            writeln!(w)?;
+        }
+        OutputFormat::JsonLines => {
+            for row in rows {
+                serde_json::to_writer(&mut w, &row)?;
+                writeln!(w)?;
+            }
+        }
+        OutputFormat::Table => write_table(rows, w)?,
+        #[cfg(feature = "parquet")]
+        OutputFormat::Parquet => {
+            // The Parquet writer needs a writer it can send between threads,
+            // which stdout's lock is not
+            let mut buffer = Vec::new();
+            crate::columnar::write_parquet(rows, &mut buffer).map_err(io::Error::other)?;
+            w.write_all(&buffer)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_table<W, I>(rows: I, mut w: W) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = AccountRow>,
+{
+    let cells: Vec<[String; 5]> = rows
+        .into_iter()
+        .map(|row| {
+            [
+                row.client.to_string(),
+                row.available.to_string(),
+                row.held.to_string(),
+                row.total.to_string(),
+                row.locked.to_string(),
+            ]
+        })
+        .collect();
+
+    let mut widths = HEADER.map(str::len);
+    for row in &cells {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let line = |w: &mut W, row: [&str; 5]| -> io::Result<()> {
+        let padded: Vec<_> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:>width$}"))
+            .collect();
+        writeln!(w, "{}", padded.join(" | "))
+    };
+
+    line(&mut w, HEADER)?;
+    let rule: Vec<_> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    writeln!(w, "{}", rule.join("-+-"))?;
+    for row in &cells {
+        line(&mut w, row.each_ref().map(String::as_str))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use std::{fmt::Display, io, io::Write, path::Path};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{RawTransaction, TransactionTypes};
+
+/// An input row which was skipped, either because it was not a valid record or
+/// because the [`crate::Transactor`] rejected it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Rejection {
+    /// The input the row was read from, when there is more than one. The
+    /// column is always written, and left empty otherwise.
+    pub source: Option<String>,
+    pub line: u64,
+    #[serde(rename = "type")]
+    pub transaction_type: Option<TransactionTypes>,
+    pub client: Option<u16>,
+    pub tx: Option<u32>,
+    pub amount: Option<Decimal>,
+    pub reason: String,
+}
+
+impl Rejection {
+    pub fn new(line: u64, record: &RawTransaction, reason: impl Display) -> Rejection {
+        Rejection {
+            source: None,
+            line,
+            transaction_type: record.transaction_type,
+            client: record.client,
+            tx: record.tx,
+            amount: record.amount,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// A row which could not be parsed into a [`RawTransaction`] at all.
+    pub fn malformed(line: u64, byte: u64, reason: impl Display) -> Rejection {
+        Rejection {
+            source: None,
+            line,
+            transaction_type: None,
+            client: None,
+            tx: None,
+            amount: None,
+            reason: format!("malformed row at byte {byte}: {reason}"),
+        }
+    }
+
+    pub fn with_source(mut self, source: impl Into<String>) -> Rejection {
+        self.source = Some(source.into());
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RejectFormat {
+    Csv,
+    JsonLines,
+}
+
+impl RejectFormat {
+    /// Picks JSON Lines for `.jsonl` and `.ndjson` files, CSV for anything
+    /// else. A `.json` file is refused, since rejections are written one per
+    /// line rather than as a JSON array.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<RejectFormat, String> {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("jsonl" | "ndjson") => Ok(RejectFormat::JsonLines),
+            Some("json") => Err(format!(
+                "rejects are written as JSON Lines, use a .jsonl file rather than {}",
+                path.as_ref().display()
+            )),
+            _ => Ok(RejectFormat::Csv),
+        }
+    }
+}
+
+enum Inner<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    JsonLines(W),
+}
+
+/// Writes one line per [`Rejection`] in the chosen [`RejectFormat`].
+pub struct RejectWriter<W: Write> {
+    inner: Inner<W>,
+}
+
+impl<W: Write> RejectWriter<W> {
+    pub fn new(format: RejectFormat, writer: W) -> RejectWriter<W> {
+        let inner = match format {
+            RejectFormat::Csv => Inner::Csv(Box::new(csv::Writer::from_writer(writer))),
+            RejectFormat::JsonLines => Inner::JsonLines(writer),
+        };
+
+        RejectWriter { inner }
+    }
+
+    pub fn write(&mut self, rejection: &Rejection) -> io::Result<()> {
+        match &mut self.inner {
+            Inner::Csv(writer) => writer.serialize(rejection)?,
+            Inner::JsonLines(writer) => {
+                serde_json::to_writer(&mut *writer, rejection)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.inner {
+            Inner::Csv(writer) => writer.flush(),
+            Inner::JsonLines(writer) => writer.flush(),
+        }
+    }
+}
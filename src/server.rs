@@ -0,0 +1,222 @@
+use std::{future::Future, io, net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc::Sender, oneshot},
+    task::JoinSet,
+};
+
+use crate::{
+    ingest::parse_line, validate::validate_fields, write_rows, AccountRow, Ingested, LedgerEntry,
+    LedgerStore, Outcome, OutputFormat, RawTransaction, Rejection, Settled, StoreError,
+    TransactionError, Transactor,
+};
+
+/// A request for the consumer's current state, answered through the oneshot
+/// channel it carries.
+#[derive(Debug)]
+pub enum Query {
+    /// Every account, in client id order
+    Balances(oneshot::Sender<Vec<AccountRow>>),
+    /// One client's account, if it has one
+    Account {
+        client: u16,
+        reply: oneshot::Sender<Option<AccountRow>>,
+    },
+    /// Every deposit and withdrawal made by a client, in tx order, if it has
+    /// an account
+    Transactions {
+        client: u16,
+        reply: oneshot::Sender<Result<Option<Vec<LedgerEntry>>, StoreError>>,
+    },
+    /// The client and tx of every open dispute, in client id then tx order
+    Disputes(oneshot::Sender<Vec<(u16, u32)>>),
+    /// A record to process straight away, bypassing any
+    /// [`DisputeWindow`](crate::DisputeWindow), so that the sender learns its
+    /// outcome
+    Submit {
+        source: Arc<str>,
+        record: RawTransaction,
+        reply: oneshot::Sender<Result<Outcome, TransactionError>>,
+    },
+}
+
+impl Query {
+    /// Answers the query from `transactor`. A submitted record is also
+    /// returned as [`Settled`], so that the caller can log or reject it like
+    /// any other.
+    pub fn answer<L: LedgerStore>(self, transactor: &mut Transactor<L>) -> Option<Settled> {
+        // The requester may have gone away since, so replies are best effort
+        match self {
+            Query::Balances(reply) => {
+                let rows = transactor
+                    .accounts()
+                    .map(|(client, account)| AccountRow::new(client, account))
+                    .collect();
+                let _ = reply.send(rows);
+            }
+            Query::Account { client, reply } => {
+                let row = transactor
+                    .account(client)
+                    .map(|account| AccountRow::new(client, account));
+                let _ = reply.send(row);
+            }
+            Query::Transactions { client, reply } => {
+                let _ = reply.send(transactor.transactions(client));
+            }
+            Query::Disputes(reply) => {
+                let disputes = transactor
+                    .accounts()
+                    .flat_map(|(client, account)| {
+                        account.disputes.iter().map(move |tx| (client, *tx))
+                    })
+                    .collect();
+                let _ = reply.send(disputes);
+            }
+            Query::Submit {
+                source,
+                record,
+                reply,
+            } => {
+                let result = transactor.process_a_record(record);
+                let _ = reply.send(result.clone());
+                return Some(Settled {
+                    source: Some(source),
+                    line: 0,
+                    record,
+                    result,
+                });
+            }
+        }
+
+        None
+    }
+}
+
+/// Accepts connections on `listener` until `shutdown` completes, reading
+/// newline delimited transactions from each and sending them down `records`.
+///
+/// Every line is either a JSON object or a CSV row of `type, client, tx,
+/// amount`. A CSV header of those columns is skipped if it is the first line. `balances` (optionally followed by an output
+/// format, e.g. `balances json`) asks for the account report through `queries`
+/// and writes it back, followed by an empty line. A line which cannot be
+/// parsed is rejected like a malformed row and reported back to the client as
+/// `error: ...`.
+///
+/// Reading waits while the channel is full, so a client which sends faster
+/// than the consumer can process is slowed down by TCP flow control.
+pub async fn serve(
+    listener: TcpListener,
+    records: Sender<Ingested>,
+    queries: Sender<Query>,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = accepted?;
+                connections.spawn(handle(stream, peer, records.clone(), queries.clone()));
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            () = &mut shutdown => break,
+        }
+    }
+
+    // Dropping the connections drops their senders, letting the consumer finish
+    connections.shutdown().await;
+    Ok(())
+}
+
+async fn handle(
+    stream: TcpStream,
+    peer: SocketAddr,
+    records: Sender<Ingested>,
+    queries: Sender<Query>,
+) -> io::Result<()> {
+    let source: Arc<str> = format!("tcp:{peer}").into();
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut line_number = 0;
+    let mut byte = 0;
+
+    while let Some(line) = lines.next_line().await? {
+        line_number += 1;
+        let position = byte;
+        byte += line.len() as u64 + 1;
+
+        // Blank lines are skipped, as is a CSV header on the first line
+        let line = line.trim();
+        if line.is_empty() || (line_number == 1 && is_header(line)) {
+            continue;
+        }
+
+        if let Some(format) = line.strip_prefix("balances") {
+            let format = match format.trim() {
+                "" => OutputFormat::default(),
+                format => match format.parse() {
+                    Ok(format) => format,
+                    Err(e) => {
+                        writer.write_all(format!("error: {e}\n").as_bytes()).await?;
+                        continue;
+                    }
+                },
+            };
+
+            let (reply, rows) = oneshot::channel();
+            if queries.send(Query::Balances(reply)).await.is_err() {
+                break;
+            }
+            let Ok(rows) = rows.await else {
+                break;
+            };
+
+            let mut buffer = Vec::new();
+            write_rows(format, rows, &mut buffer)?;
+            buffer.push(b'\n');
+            writer.write_all(&buffer).await?;
+            continue;
+        }
+
+        let ingested = match parse_line(line).map(|record| (validate_fields(&record), record)) {
+            Ok((Ok(()), record)) => Ingested::Record {
+                source: Some(source.clone()),
+                line: line_number,
+                timestamp: None,
+                record,
+            },
+            Ok((Err(error), record)) => {
+                writer
+                    .write_all(format!("error: line {line_number}: {error}\n").as_bytes())
+                    .await?;
+                Ingested::Rejected(
+                    Rejection::new(line_number, &record, error).with_source(&*source),
+                )
+            }
+            Err(reason) => {
+                writer
+                    .write_all(format!("error: line {line_number}: {reason}\n").as_bytes())
+                    .await?;
+                Ingested::Rejected(
+                    Rejection::malformed(line_number, position, reason).with_source(&*source),
+                )
+            }
+        };
+
+        if records.send(ingested).await.is_err() {
+            // The consumer has gone away
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn is_header(line: &str) -> bool {
+    line.split(',')
+        .map(str::trim)
+        .eq(["type", "client", "tx", "amount"])
+}
@@ -0,0 +1,197 @@
+use std::{collections::HashMap, error::Error, fmt, mem, sync::Arc};
+
+use tokio::{
+    sync::mpsc::{channel, unbounded_channel, Sender, UnboundedReceiver},
+    task::{JoinError, JoinSet},
+};
+
+use crate::{
+    Command, LedgerStore, RawTransaction, Settled, StoreError, TransactionError, Transactor,
+};
+
+/// Records are handed to the shards in batches of this size, so that the cost
+/// of the channel is shared between many records.
+const BATCH: usize = 256;
+
+/// Records along with the client which owns their tx on another shard, if one
+/// does.
+type Batch = Vec<(Option<Arc<str>>, u64, RawTransaction, Option<u16>)>;
+
+/// Processes records on several [`Transactor`] shards at once. Every client is
+/// owned by shard `client % N`, so each shard works on its own accounts and the
+/// shards never need to coordinate.
+///
+/// Tx ids are unique across all clients, so the router keeps the owner of every
+/// deposit and withdrawal it has routed. A record which conflicts with a tx
+/// owned by another shard is sent to its shard along with that tx's owner, so
+/// that the shard rejects it just as a single transactor would, after checking
+/// the client's account. The one difference to a single transactor is that a tx id stays
+/// taken by the first shard it was routed to, even if that shard rejected it.
+///
+/// The owners are kept in memory for the whole run, one entry per deposit and
+/// withdrawal, on top of the shards' own ledgers. The shards always keep their
+/// ledgers in memory too, so a run which needs a disk ledger cannot be sharded.
+pub struct ShardedEngine {
+    batches: Vec<Batch>,
+    shards: Vec<Sender<Batch>>,
+    workers: JoinSet<(usize, Transactor)>,
+    owners: HashMap<u32, u16>,
+}
+
+impl ShardedEngine {
+    /// Starts `shards` workers, each with a transactor made by `new_shard`, and
+    /// returns the engine along with the channel every rejected record is
+    /// reported on.
+    pub fn spawn(
+        shards: usize,
+        new_shard: impl Fn() -> Transactor,
+    ) -> (ShardedEngine, UnboundedReceiver<Settled>) {
+        let shards = shards.max(1);
+        let (rejected_tx, rejected_rx) = unbounded_channel();
+        let mut workers = JoinSet::new();
+
+        let senders = (0..shards)
+            .map(|i| {
+                let (tx, mut rx) = channel::<Batch>(4);
+                let mut transactor = new_shard();
+                let rejected_tx = rejected_tx.clone();
+
+                workers.spawn(async move {
+                    while let Some(batch) = rx.recv().await {
+                        for (source, line, record, owner) in batch {
+                            let result = match owner {
+                                Some(owner) => transactor.process_taken(record, owner),
+                                None => transactor.process_a_record(record),
+                            };
+                            if let Err(e) = result {
+                                // Nobody listening for rejections is not a reason to stop
+                                let _ = rejected_tx.send(Settled {
+                                    source,
+                                    line,
+                                    record,
+                                    result: Err(e),
+                                });
+                            }
+                        }
+                    }
+
+                    (i, transactor)
+                });
+
+                tx
+            })
+            .collect();
+
+        let engine = ShardedEngine {
+            batches: (0..shards).map(|_| Vec::with_capacity(BATCH)).collect(),
+            shards: senders,
+            workers,
+            owners: HashMap::new(),
+        };
+        (engine, rejected_rx)
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard_of(&self, client: u16) -> usize {
+        usize::from(client) % self.shards.len()
+    }
+
+    /// Routes `record` to the shard which owns its client. Returns the record
+    /// straight away if it is not a valid record.
+    pub async fn process(
+        &mut self,
+        source: Option<Arc<str>>,
+        line: u64,
+        record: RawTransaction,
+    ) -> Option<Settled> {
+        match self.route(&record) {
+            Ok((shard, owner)) => {
+                self.batches[shard].push((source, line, record, owner));
+                if self.batches[shard].len() >= BATCH {
+                    self.send(shard).await;
+                }
+                None
+            }
+            Err(e) => Some(Settled {
+                source,
+                line,
+                record,
+                result: Err(e),
+            }),
+        }
+    }
+
+    /// The shard `record` goes to, and the owner of its tx if that is a client
+    /// of another shard.
+    fn route(&mut self, record: &RawTransaction) -> Result<(usize, Option<u16>), TransactionError> {
+        let command = Command::try_from(*record)?;
+        let (client, tx) = (command.client(), command.tx());
+        let shard = self.shard_of(client);
+
+        // Conflicts within a shard are left to the shard, which knows whether
+        // the earlier record was accepted
+        match (command, self.owners.get(&tx)) {
+            (Command::Deposit { .. } | Command::Withdrawal { .. }, None) => {
+                self.owners.insert(tx, client);
+            }
+            (_, Some(&owner)) if self.shard_of(owner) != shard => return Ok((shard, Some(owner))),
+            _ => {}
+        }
+
+        Ok((shard, None))
+    }
+
+    async fn send(&mut self, shard: usize) {
+        let batch = mem::replace(&mut self.batches[shard], Vec::with_capacity(BATCH));
+        // A shard only stops early if it panicked, which finish reports
+        let _ = self.shards[shard].send(batch).await;
+    }
+
+    /// Waits for every shard to process its records, then moves their accounts
+    /// and ledgers into `transactor`.
+    pub async fn finish<L: LedgerStore>(
+        mut self,
+        transactor: &mut Transactor<L>,
+    ) -> Result<(), ShardError> {
+        for shard in 0..self.shards.len() {
+            if !self.batches[shard].is_empty() {
+                self.send(shard).await;
+            }
+        }
+        // Closing the channels lets the workers finish
+        self.shards.clear();
+
+        let mut shards = Vec::new();
+        while let Some(worker) = self.workers.join_next().await {
+            shards.push(worker.map_err(ShardError::Worker)?);
+        }
+        shards.sort_unstable_by_key(|(i, _)| *i);
+
+        for (_, shard) in shards {
+            transactor.absorb(shard).map_err(ShardError::Storage)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ShardError {
+    /// A shard's worker panicked
+    Worker(JoinError),
+    Storage(StoreError),
+}
+
+impl fmt::Display for ShardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShardError::Worker(e) => write!(f, "shard failed: {e}"),
+            ShardError::Storage(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for ShardError {}
@@ -0,0 +1,311 @@
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    fmt, io,
+    io::{Read, Write},
+};
+
+use rust_decimal::Decimal;
+use serde::{
+    de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+
+use crate::{
+    Account, DisputeState, EntryKind, LedgerEntry, LedgerStore, StoreError, Transactor, Unlock,
+};
+
+/// The snapshot format written by [`Transactor::save_snapshot`]. Bumped
+/// whenever the format changes, snapshots of any other version are refused.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// The snapshot is not valid JSON, or does not match the format of its version
+    Format(serde_json::Error),
+    UnsupportedVersion(u32),
+    Storage(StoreError),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "failed to access snapshot: {e}"),
+            SnapshotError::Format(e) => write!(f, "invalid snapshot: {e}"),
+            SnapshotError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported snapshot version {version}, expected {SNAPSHOT_VERSION}"
+            ),
+            SnapshotError::Storage(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for SnapshotError {
+    fn from(e: serde_json::Error) -> Self {
+        if e.is_io() {
+            SnapshotError::Io(e.into())
+        } else {
+            SnapshotError::Format(e)
+        }
+    }
+}
+
+impl From<StoreError> for SnapshotError {
+    fn from(e: StoreError) -> Self {
+        SnapshotError::Storage(e)
+    }
+}
+
+/// The fields of a snapshot. The version comes first, so that the rest is only
+/// read once it is known to be in the expected format.
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "lowercase")]
+enum Field {
+    Version,
+    Wal,
+    Accounts,
+    Ledger,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Serialize)]
+struct AccountSnapshot {
+    client: u16,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+    disputes: BTreeSet<u32>,
+    unlocks: Vec<Unlock>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct EntrySnapshot {
+    tx: u32,
+    #[serde(rename = "type")]
+    kind: EntryKind,
+    client: u16,
+    amount: Decimal,
+    state: DisputeState,
+}
+
+impl<L: LedgerStore> Transactor<L> {
+    /// Writes every account and ledger entry to `w` as a versioned JSON
+    /// snapshot, which [`Transactor::restore_snapshot`] can continue from. The
+    /// snapshot is written a piece at a time, so that the ledger is never held
+    /// in memory.
+    pub fn save_snapshot<W: Write>(&self, w: W) -> Result<(), SnapshotError> {
+        self.save_snapshot_at(w, 0)
+    }
+
+    /// Writes a snapshot as [`Transactor::save_snapshot`] does, which holds
+    /// every entry of a [`WriteAheadLog`](crate::WriteAheadLog) up to `wal_seq`.
+    pub fn save_snapshot_at<W: Write>(&self, mut w: W, wal_seq: u64) -> Result<(), SnapshotError> {
+        let accounts = self
+            .accounts()
+            .map(|(client, account)| AccountSnapshot {
+                client,
+                available: account.available,
+                held: account.held,
+                total: account.total,
+                locked: account.locked,
+                disputes: account.disputes.clone(),
+                unlocks: account.unlocks.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        write!(
+            w,
+            "{{\"version\":{SNAPSHOT_VERSION},\"wal\":{wal_seq},\"accounts\":"
+        )?;
+        serde_json::to_writer(&mut w, &accounts)?;
+        write!(w, ",\"ledger\":[")?;
+        for (i, entry) in self.ledger.entries()?.enumerate() {
+            let (tx, entry) = entry?;
+            if i > 0 {
+                write!(w, ",")?;
+            }
+            let entry = EntrySnapshot {
+                tx,
+                kind: entry.kind,
+                client: entry.client,
+                amount: entry.amount,
+                state: entry.state,
+            };
+            serde_json::to_writer(&mut w, &entry)?;
+        }
+        writeln!(w, "]}}")?;
+        w.flush()?;
+
+        Ok(())
+    }
+
+    /// Loads the accounts and ledger entries of a snapshot written by
+    /// [`Transactor::save_snapshot`]. Accounts in the snapshot replace any with
+    /// the same client id, and its ledger entries are added to the store as
+    /// they are read, so that the ledger is never held in memory.
+    ///
+    /// Returns the write-ahead log entry the snapshot was saved at, which is 0
+    /// for one saved without a log.
+    pub fn restore_snapshot<R: Read>(&mut self, r: R) -> Result<u64, SnapshotError> {
+        let mut restore = Restore {
+            transactor: self,
+            wal_seq: 0,
+            error: None,
+        };
+        let mut deserializer = serde_json::Deserializer::from_reader(r);
+        let result = deserializer
+            .deserialize_map(&mut restore)
+            .and_then(|()| deserializer.end());
+
+        match restore.error {
+            Some(e) => Err(e),
+            None => Ok(result.map(|()| restore.wal_seq)?),
+        }
+    }
+}
+
+/// Reads a snapshot into a transactor, an account or ledger entry at a time.
+struct Restore<'a, L: LedgerStore> {
+    transactor: &'a mut Transactor<L>,
+    wal_seq: u64,
+    /// A failure other than in the snapshot's JSON, which the deserializer can
+    /// only report as a message
+    error: Option<SnapshotError>,
+}
+
+impl<L: LedgerStore> Restore<'_, L> {
+    fn fail<E: de::Error>(&mut self, error: SnapshotError) -> E {
+        let e = E::custom(&error);
+        self.error = Some(error);
+        e
+    }
+}
+
+impl<'de, L: LedgerStore> Visitor<'de> for &mut Restore<'_, L> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a snapshot")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let mut version = None;
+        let (mut accounts, mut ledger) = (false, false);
+
+        while let Some(field) = map.next_key()? {
+            match field {
+                Field::Version => {
+                    let found = map.next_value()?;
+                    if found != SNAPSHOT_VERSION {
+                        return Err(self.fail(SnapshotError::UnsupportedVersion(found)));
+                    }
+                    version = Some(found);
+                }
+                Field::Wal => self.wal_seq = map.next_value()?,
+                Field::Accounts | Field::Ledger if version.is_none() => {
+                    return Err(de::Error::custom("the version must come first"));
+                }
+                Field::Accounts => {
+                    map.next_value_seed(Section {
+                        restore: &mut *self,
+                        field: Field::Accounts,
+                    })?;
+                    accounts = true;
+                }
+                Field::Ledger => {
+                    map.next_value_seed(Section {
+                        restore: &mut *self,
+                        field: Field::Ledger,
+                    })?;
+                    ledger = true;
+                }
+                Field::Other => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+
+        if version.is_none() {
+            return Err(de::Error::missing_field("version"));
+        }
+        if !accounts {
+            return Err(de::Error::missing_field("accounts"));
+        }
+        if !ledger {
+            return Err(de::Error::missing_field("ledger"));
+        }
+        Ok(())
+    }
+}
+
+/// The accounts or the ledger of a snapshot, each stored as it is read.
+struct Section<'r, 'a, L: LedgerStore> {
+    restore: &'r mut Restore<'a, L>,
+    field: Field,
+}
+
+impl<'de, L: LedgerStore> DeserializeSeed<'de> for Section<'_, '_, L> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, L: LedgerStore> Visitor<'de> for Section<'_, '_, L> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a list of accounts or ledger entries")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let transactor = &mut *self.restore.transactor;
+
+        if let Field::Accounts = self.field {
+            while let Some(account) = seq.next_element::<AccountSnapshot>()? {
+                transactor.client_accounts.insert(
+                    account.client,
+                    Account {
+                        available: account.available,
+                        held: account.held,
+                        total: account.total,
+                        locked: account.locked,
+                        disputes: account.disputes,
+                        unlocks: account.unlocks,
+                    },
+                );
+            }
+            return Ok(());
+        }
+
+        while let Some(entry) = seq.next_element::<EntrySnapshot>()? {
+            let inserted = transactor.ledger.insert(
+                entry.tx,
+                LedgerEntry {
+                    kind: entry.kind,
+                    client: entry.client,
+                    tx: entry.tx,
+                    amount: entry.amount,
+                    state: entry.state,
+                },
+            );
+            if let Err(e) = inserted {
+                return Err(self.restore.fail(e.into()));
+            }
+        }
+        Ok(())
+    }
+}
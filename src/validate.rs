@@ -0,0 +1,139 @@
+use std::{error::Error, fmt};
+
+use rust_decimal::Decimal;
+
+use crate::{Command, RawTransaction};
+
+/// The decimal places amounts are limited to by default, which is the
+/// precision of the account report.
+pub const MAX_SCALE: u32 = 4;
+
+/// The reason a record is not a well formed transaction, whatever the state of
+/// the accounts it would be applied to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    MissingType,
+    MissingClient,
+    MissingTx,
+    /// A deposit or withdrawal without an amount
+    MissingAmount,
+    /// A dispute, resolve or chargeback with an amount. They always refer to
+    /// the amount of the transaction they name.
+    UnexpectedAmount,
+    NegativeAmount,
+    ZeroAmount,
+    /// An amount with more decimal places than the [`AmountPolicy`] allows
+    ExcessPrecision {
+        scale: u32,
+    },
+    /// An amount above the [`AmountPolicy`]'s maximum
+    AmountTooLarge {
+        max: Decimal,
+    },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::MissingType => f.write_str("record is missing its type"),
+            ValidationError::MissingClient => f.write_str("record is missing its client"),
+            ValidationError::MissingTx => f.write_str("record is missing its tx"),
+            ValidationError::MissingAmount => f.write_str("amount is missing"),
+            ValidationError::UnexpectedAmount => {
+                f.write_str("only deposits and withdrawals can have an amount")
+            }
+            ValidationError::NegativeAmount => f.write_str("amount is negative"),
+            ValidationError::ZeroAmount => f.write_str("amount is zero"),
+            ValidationError::ExcessPrecision { scale } => {
+                write!(f, "amount has more than {scale} decimal places")
+            }
+            ValidationError::AmountTooLarge { max } => {
+                write!(f, "amount is larger than the maximum of {max}")
+            }
+        }
+    }
+}
+
+impl Error for ValidationError {}
+
+/// What is done with an amount which has more decimal places than the
+/// [`AmountPolicy`] allows.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rounding {
+    /// The record is rejected
+    #[default]
+    Reject,
+    /// The amount is rounded to the allowed scale, half to even, as the report
+    /// does
+    Round,
+}
+
+/// The amounts a [`Transactor`](crate::Transactor) accepts on deposits and
+/// withdrawals. Amounts are always positive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AmountPolicy {
+    /// The most decimal places an amount may have
+    pub scale: u32,
+    pub rounding: Rounding,
+    /// The largest amount a single deposit or withdrawal may move, if any
+    pub max_amount: Option<Decimal>,
+}
+
+impl Default for AmountPolicy {
+    fn default() -> Self {
+        AmountPolicy {
+            scale: MAX_SCALE,
+            rounding: Rounding::default(),
+            max_amount: None,
+        }
+    }
+}
+
+impl AmountPolicy {
+    /// Checks `record` as [`validate`] does, against this policy.
+    pub fn validate(&self, record: &RawTransaction) -> Result<(), ValidationError> {
+        match Command::try_from(*record)?.amount() {
+            Some(amount) => self.check(amount).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks the amount of a deposit or withdrawal and returns it rounded, if
+    /// this policy rounds.
+    pub fn check(&self, amount: Decimal) -> Result<Decimal, ValidationError> {
+        if amount.is_sign_negative() && !amount.is_zero() {
+            return Err(ValidationError::NegativeAmount);
+        }
+
+        // Trailing zeros do not count, 1.50000 is as precise as 1.5
+        let amount = match self.rounding {
+            _ if amount.normalize().scale() <= self.scale => amount,
+            Rounding::Reject => return Err(ValidationError::ExcessPrecision { scale: self.scale }),
+            Rounding::Round => amount.round_dp(self.scale),
+        };
+
+        // A small enough amount can be rounded to zero
+        if amount.is_zero() {
+            return Err(ValidationError::ZeroAmount);
+        }
+        if let Some(max) = self.max_amount {
+            if amount > max {
+                return Err(ValidationError::AmountTooLarge { max });
+            }
+        }
+
+        Ok(amount)
+    }
+}
+
+/// Checks that `record` has the fields its type calls for, and only those, and
+/// that its amount is one the default [`AmountPolicy`] accepts.
+pub fn validate(record: &RawTransaction) -> Result<(), ValidationError> {
+    AmountPolicy::default().validate(record)
+}
+
+/// Checks that `record` has the fields its type calls for, and only those,
+/// leaving the amount itself to the [`AmountPolicy`].
+pub(crate) fn validate_fields(record: &RawTransaction) -> Result<(), ValidationError> {
+    Command::try_from(*record).map(|_| ())
+}
@@ -0,0 +1,278 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt,
+    fs::{File, OpenOptions},
+    io,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{LedgerStore, RawTransaction, TransactionError, Transactor};
+
+/// How often the [`WriteAheadLog`] forces appended records to disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every record. Nothing which was accepted is lost in a crash.
+    #[default]
+    Always,
+    /// After every N records. Up to N - 1 accepted records can be lost in a crash.
+    Every(u32),
+    /// Only when the log is synced explicitly, leaving the rest to the OS.
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => match s.parse() {
+                Ok(0) | Err(_) => Err(format!(
+                    "unknown fsync policy '{s}', expected always, never or a record count"
+                )),
+                Ok(n) => Ok(FsyncPolicy::Every(n)),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WalError {
+    Io(io::Error),
+    /// A line before the end of the log could not be read back
+    Corrupt {
+        line: u64,
+        reason: String,
+    },
+    /// A logged record was rejected on replay, so the log does not belong to
+    /// the state it is being replayed into
+    Rejected {
+        line: u64,
+        error: TransactionError,
+    },
+}
+
+impl fmt::Display for WalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalError::Io(e) => write!(f, "failed to access write-ahead log: {e}"),
+            WalError::Corrupt { line, reason } => {
+                write!(f, "corrupt write-ahead log at line {line}: {reason}")
+            }
+            WalError::Rejected { line, error } => {
+                write!(
+                    f,
+                    "write-ahead log record at line {line} was rejected on replay: {error}"
+                )
+            }
+        }
+    }
+}
+
+impl Error for WalError {}
+
+impl From<io::Error> for WalError {
+    fn from(e: io::Error) -> Self {
+        WalError::Io(e)
+    }
+}
+
+/// A line of the log: where a record was read from, and the record itself if
+/// the transactor accepted it. Entries are numbered from 1, and the numbering
+/// carries on when the log is reset.
+#[derive(Deserialize, Serialize)]
+struct Logged<'a> {
+    seq: u64,
+    #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+    source: Option<Cow<'a, str>>,
+    line: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    record: Option<RawTransaction>,
+}
+
+/// The lines of a source which are in the log, as ranges of consecutive lines
+/// keyed by their first line.
+#[derive(Debug, Default)]
+struct Lines(BTreeMap<u64, u64>);
+
+impl Lines {
+    fn insert(&mut self, line: u64) {
+        let (mut first, mut last) = (line, line);
+        if let Some((&start, &end)) = self.0.range(..=line).next_back() {
+            if line <= end {
+                return;
+            }
+            if end + 1 == line {
+                first = start;
+            }
+        }
+        if let Some(end) = self.0.remove(&(line + 1)) {
+            last = end;
+        }
+        self.0.insert(first, last);
+    }
+
+    fn contains(&self, line: u64) -> bool {
+        self.0
+            .range(..=line)
+            .next_back()
+            .is_some_and(|(_, &end)| line <= end)
+    }
+}
+
+/// An append-only log of every record a [`Transactor`] has dealt with, one JSON
+/// object per line. Accepted records are logged in full, rejected ones only by
+/// their source and line. Replaying the log into the state the transactor
+/// started from brings it back to where it was, and the logged lines tell which
+/// parts of the input it has already seen.
+pub struct WriteAheadLog {
+    file: BufWriter<File>,
+    fsync: FsyncPolicy,
+    unsynced: u32,
+    /// The lines of each source which were in the log when it was recovered
+    logged: HashMap<String, Lines>,
+    /// The number of the last entry written
+    seq: u64,
+}
+
+impl WriteAheadLog {
+    /// Opens the log at `path`, creating it if it does not exist, and replays
+    /// every accepted record already in it into `transactor`. Returns the log,
+    /// ready for appending, and the number of records replayed.
+    ///
+    /// Entries up to `after` are already part of the transactor's state and are
+    /// not replayed. This is the [`WriteAheadLog::seq`] a snapshot the state was
+    /// restored from was saved at, so that a crash between saving a snapshot
+    /// and resetting the log does not apply its records twice.
+    ///
+    /// A last line which was only partly written before a crash is dropped from
+    /// the log. Any other line which cannot be read back is an error.
+    pub fn recover<L: LedgerStore>(
+        path: impl AsRef<Path>,
+        fsync: FsyncPolicy,
+        transactor: &mut Transactor<L>,
+        after: u64,
+    ) -> Result<(WriteAheadLog, u64), WalError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let mut reader = BufReader::new(&file);
+        let mut buffer = Vec::new();
+        let mut complete = 0;
+        let mut line = 0;
+        let mut replayed = 0;
+        let mut logged: HashMap<String, Lines> = HashMap::new();
+        let mut seq = after;
+
+        loop {
+            buffer.clear();
+            let read = reader.read_until(b'\n', &mut buffer)?;
+            if read == 0 || buffer.last() != Some(&b'\n') {
+                // The end of the log, possibly with a torn record
+                break;
+            }
+            line += 1;
+
+            let entry: Logged = serde_json::from_slice(&buffer).map_err(|e| WalError::Corrupt {
+                line,
+                reason: e.to_string(),
+            })?;
+            seq = seq.max(entry.seq);
+            if let Some(record) = entry.record.filter(|_| entry.seq > after) {
+                transactor
+                    .process_a_record(record)
+                    .map_err(|error| WalError::Rejected { line, error })?;
+                replayed += 1;
+            }
+            if let Some(source) = entry.source {
+                logged
+                    .entry(source.into_owned())
+                    .or_default()
+                    .insert(entry.line);
+            }
+            complete += read as u64;
+        }
+
+        // Cut off any torn record so that the next one starts on a line of its own
+        file.set_len(complete)?;
+
+        let wal = WriteAheadLog {
+            file: BufWriter::new(file),
+            fsync,
+            unsynced: 0,
+            logged,
+            seq,
+        };
+        Ok((wal, replayed))
+    }
+
+    /// The number of the last entry in the log, or of the last one before it
+    /// was reset. A snapshot saved now holds every entry up to it.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Whether `line` of `source` was in the log when it was recovered. Such a
+    /// line has already been dealt with, so an input which is read again after
+    /// a crash should skip it.
+    pub fn is_logged(&self, source: &str, line: u64) -> bool {
+        self.logged
+            .get(source)
+            .is_some_and(|lines| lines.contains(line))
+    }
+
+    /// Appends the record at `line` of `source` once the transactor is done
+    /// with it, syncing the log if the [`FsyncPolicy`] calls for it. `record`
+    /// is the record if it was accepted, and `None` if it was rejected.
+    pub fn append(
+        &mut self,
+        source: Option<&str>,
+        line: u64,
+        record: Option<&RawTransaction>,
+    ) -> io::Result<()> {
+        self.seq += 1;
+        let entry = Logged {
+            seq: self.seq,
+            source: source.map(Cow::Borrowed),
+            line,
+            record: record.copied(),
+        };
+        serde_json::to_writer(&mut self.file, &entry)?;
+        self.file.write_all(b"\n")?;
+        self.unsynced = self.unsynced.saturating_add(1);
+
+        match self.fsync {
+            FsyncPolicy::Always => self.sync(),
+            FsyncPolicy::Every(n) if self.unsynced >= n => self.sync(),
+            FsyncPolicy::Every(_) | FsyncPolicy::Never => Ok(()),
+        }
+    }
+
+    /// Writes every appended record through to disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Empties the log, once the state it leads to has been saved elsewhere
+    /// (e.g. in a snapshot).
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().set_len(0)?;
+        self.file.get_ref().sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+}
@@ -1,5 +1,7 @@
 #[cfg(test)]
 mod tests {
+    use proptest::{prelude::*, sample::select};
+    use rust_decimal::Decimal;
     use rust_decimal_macros::dec;
 
     use transactions::*;
@@ -21,13 +23,13 @@ mod tests {
             amount: Some(dec!(5.0)),
         };
 
-        transaction_processor.process_a_record(trans_1);
-        transaction_processor.process_a_record(trans_2);
-        assert_eq!(Some(dec!(5.0)),Some(transaction_processor.client_accounts.get(&1).unwrap().available));
+        transaction_processor.process_a_record(trans_1).unwrap();
+        transaction_processor.process_a_record(trans_2).unwrap();
+        assert_eq!(Some(dec!(5.0)),Some(transaction_processor.balance(1).unwrap().available));
     }
 
     #[test]
-    fn deposit_of_zero_creates_new_account() {
+    fn deposit_of_zero_is_rejected() {
         let mut transaction_processor = Transactor::new();
 
         let trans_1 = Transaction {
@@ -37,9 +39,8 @@ mod tests {
             amount: Some(dec!(0.0)),
         };
 
-        transaction_processor.process_a_record(trans_1);
-        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.client_accounts.get(&1).unwrap().available));
-        assert!(!transaction_processor.client_accounts.is_empty());
+        assert_eq!(Err(TransactionError::Invalid(ValidationError::ZeroAmount)), transaction_processor.process_a_record(trans_1));
+        assert!(transaction_processor.accounts().next().is_none());
     }
 
     #[test]
@@ -59,10 +60,10 @@ mod tests {
             amount: Some(dec!(20.0)),
         };
 
-        transaction_processor.process_a_record(trans_1);
-        transaction_processor.process_a_record(trans_2);
+        transaction_processor.process_a_record(trans_1).unwrap();
+        transaction_processor.process_a_record(trans_2).unwrap();
 
-        assert_eq!(Some(dec!(30.0)),Some(transaction_processor.client_accounts.get(&1).unwrap().available));
+        assert_eq!(Some(dec!(30.0)),Some(transaction_processor.balance(1).unwrap().available));
     }
 
     #[test]
@@ -78,11 +79,11 @@ mod tests {
             amount: Some(dec!(10.0)),
         };
 
-        transaction_processor.process_a_record(trans);
-        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().available));
-        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().held));
-        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().total));
-        assert_eq!(Some(false),Some(transaction_processor.client_accounts.get(&account_number).unwrap().locked));
+        transaction_processor.process_a_record(trans).unwrap();
+        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.balance(account_number).unwrap().available));
+        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.balance(account_number).unwrap().held));
+        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.balance(account_number).unwrap().total));
+        assert_eq!(Some(false),Some(transaction_processor.balance(account_number).unwrap().locked));
 
         tx_number += 1;
         trans = Transaction {
@@ -92,11 +93,11 @@ mod tests {
             amount: Some(dec!(5.0)),
         };
 
-        transaction_processor.process_a_record(trans);
-        assert_eq!(Some(dec!(15.0)), Some(transaction_processor.client_accounts.get(&account_number).unwrap().available));
-        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().held));
-        assert_eq!(Some(dec!(15.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().total));
-        assert_eq!(Some(false),Some(transaction_processor.client_accounts.get(&account_number).unwrap().locked));
+        transaction_processor.process_a_record(trans).unwrap();
+        assert_eq!(Some(dec!(15.0)), Some(transaction_processor.balance(account_number).unwrap().available));
+        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.balance(account_number).unwrap().held));
+        assert_eq!(Some(dec!(15.0)),Some(transaction_processor.balance(account_number).unwrap().total));
+        assert_eq!(Some(false),Some(transaction_processor.balance(account_number).unwrap().locked));
 
         tx_number += 1;
         trans = Transaction {
@@ -106,11 +107,11 @@ mod tests {
             amount: Some(dec!(6.0)),
         };
 
-        transaction_processor.process_a_record(trans);
-        assert_eq!(Some(dec!(9.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().available));
-        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().held));
-        assert_eq!(Some(dec!(9.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().total));
-        assert_eq!(Some(false),Some(transaction_processor.client_accounts.get(&account_number).unwrap().locked));
+        transaction_processor.process_a_record(trans).unwrap();
+        assert_eq!(Some(dec!(9.0)),Some(transaction_processor.balance(account_number).unwrap().available));
+        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.balance(account_number).unwrap().held));
+        assert_eq!(Some(dec!(9.0)),Some(transaction_processor.balance(account_number).unwrap().total));
+        assert_eq!(Some(false),Some(transaction_processor.balance(account_number).unwrap().locked));
 
         tx_number += 1;
         trans = Transaction {
@@ -120,11 +121,11 @@ mod tests {
             amount: Some(dec!(2.0)),
         };
 
-        transaction_processor.process_a_record(trans);
-        assert_eq!(Some(dec!(7.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().available));
-        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().held));
-        assert_eq!(Some(dec!(7.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().total));
-        assert_eq!(Some(false),Some(transaction_processor.client_accounts.get(&account_number).unwrap().locked));
+        transaction_processor.process_a_record(trans).unwrap();
+        assert_eq!(Some(dec!(7.0)),Some(transaction_processor.balance(account_number).unwrap().available));
+        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.balance(account_number).unwrap().held));
+        assert_eq!(Some(dec!(7.0)),Some(transaction_processor.balance(account_number).unwrap().total));
+        assert_eq!(Some(false),Some(transaction_processor.balance(account_number).unwrap().locked));
 
         trans = Transaction {
             transaction_type: Some(TransactionTypes::Dispute),
@@ -133,11 +134,11 @@ mod tests {
             amount: None,
         };
 
-        transaction_processor.process_a_record(trans);
-        assert_eq!(Some(dec!(2.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().available));
-        assert_eq!(Some(dec!(5.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().held));
-        assert_eq!(Some(dec!(7.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().total));
-        assert_eq!(Some(false),Some(transaction_processor.client_accounts.get(&account_number).unwrap().locked));
+        transaction_processor.process_a_record(trans).unwrap();
+        assert_eq!(Some(dec!(2.0)),Some(transaction_processor.balance(account_number).unwrap().available));
+        assert_eq!(Some(dec!(5.0)),Some(transaction_processor.balance(account_number).unwrap().held));
+        assert_eq!(Some(dec!(7.0)),Some(transaction_processor.balance(account_number).unwrap().total));
+        assert_eq!(Some(false),Some(transaction_processor.balance(account_number).unwrap().locked));
 
         trans = Transaction {
             transaction_type: Some(TransactionTypes::Resolve),
@@ -146,11 +147,11 @@ mod tests {
             amount: None,
         };
 
-        transaction_processor.process_a_record(trans);
-        assert_eq!(Some(dec!(7.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().available));
-        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().held));
-        assert_eq!(Some(dec!(7.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().total));
-        assert_eq!(Some(false),Some(transaction_processor.client_accounts.get(&account_number).unwrap().locked));
+        transaction_processor.process_a_record(trans).unwrap();
+        assert_eq!(Some(dec!(7.0)),Some(transaction_processor.balance(account_number).unwrap().available));
+        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.balance(account_number).unwrap().held));
+        assert_eq!(Some(dec!(7.0)),Some(transaction_processor.balance(account_number).unwrap().total));
+        assert_eq!(Some(false),Some(transaction_processor.balance(account_number).unwrap().locked));
     }
 
     #[test]
@@ -166,11 +167,11 @@ mod tests {
             amount: Some(dec!(10.0)),
         };
 
-        transaction_processor.process_a_record(trans);
-        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.client_accounts.get(&1).unwrap().available));
-        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.client_accounts.get(&1).unwrap().held));
-        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.client_accounts.get(&1).unwrap().total));
-        assert_eq!(Some(false),Some(transaction_processor.client_accounts.get(&1).unwrap().locked));
+        transaction_processor.process_a_record(trans).unwrap();
+        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.balance(1).unwrap().available));
+        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.balance(1).unwrap().held));
+        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.balance(1).unwrap().total));
+        assert_eq!(Some(false),Some(transaction_processor.balance(1).unwrap().locked));
 
         trans = Transaction {
             transaction_type: Some(TransactionTypes::Resolve),
@@ -179,12 +180,12 @@ mod tests {
             amount: None,
         };
 
-        transaction_processor.process_a_record(trans);
-        assert!(transaction_processor.client_accounts.get(&1).unwrap().disputes.is_empty());
-        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().available));
-        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().held));
-        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().total));
-        assert_eq!(Some(false),Some(transaction_processor.client_accounts.get(&account_number).unwrap().locked));
+        assert_eq!(Err(TransactionError::NotDisputed), transaction_processor.process_a_record(trans));
+        assert!(transaction_processor.open_disputes(1).is_empty());
+        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.balance(account_number).unwrap().available));
+        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.balance(account_number).unwrap().held));
+        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.balance(account_number).unwrap().total));
+        assert_eq!(Some(false),Some(transaction_processor.balance(account_number).unwrap().locked));
     }
 
     #[test]
@@ -200,11 +201,11 @@ mod tests {
             amount: Some(dec!(10.0)),
         };
 
-        transaction_processor.process_a_record(trans);
-        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().available));
-        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().held));
-        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().total));
-        assert_eq!(Some(false),Some(transaction_processor.client_accounts.get(&account_number).unwrap().locked));
+        transaction_processor.process_a_record(trans).unwrap();
+        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.balance(account_number).unwrap().available));
+        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.balance(account_number).unwrap().held));
+        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.balance(account_number).unwrap().total));
+        assert_eq!(Some(false),Some(transaction_processor.balance(account_number).unwrap().locked));
 
         tx_number += 1;
         trans = Transaction {
@@ -214,11 +215,11 @@ mod tests {
             amount: Some(dec!(5.0)),
         };
 
-        transaction_processor.process_a_record(trans);
-        assert_eq!(Some(dec!(15.0)), Some(transaction_processor.client_accounts.get(&account_number).unwrap().available));
-        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().held));
-        assert_eq!(Some(dec!(15.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().total));
-        assert_eq!(Some(false),Some(transaction_processor.client_accounts.get(&account_number).unwrap().locked));
+        transaction_processor.process_a_record(trans).unwrap();
+        assert_eq!(Some(dec!(15.0)), Some(transaction_processor.balance(account_number).unwrap().available));
+        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.balance(account_number).unwrap().held));
+        assert_eq!(Some(dec!(15.0)),Some(transaction_processor.balance(account_number).unwrap().total));
+        assert_eq!(Some(false),Some(transaction_processor.balance(account_number).unwrap().locked));
 
         tx_number += 1;
         trans = Transaction {
@@ -228,11 +229,11 @@ mod tests {
             amount: Some(dec!(6.0)),
         };
 
-        transaction_processor.process_a_record(trans);
-        assert_eq!(Some(dec!(9.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().available));
-        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().held));
-        assert_eq!(Some(dec!(9.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().total));
-        assert_eq!(Some(false),Some(transaction_processor.client_accounts.get(&account_number).unwrap().locked));
+        transaction_processor.process_a_record(trans).unwrap();
+        assert_eq!(Some(dec!(9.0)),Some(transaction_processor.balance(account_number).unwrap().available));
+        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.balance(account_number).unwrap().held));
+        assert_eq!(Some(dec!(9.0)),Some(transaction_processor.balance(account_number).unwrap().total));
+        assert_eq!(Some(false),Some(transaction_processor.balance(account_number).unwrap().locked));
 
         tx_number += 1;
         trans = Transaction {
@@ -242,11 +243,11 @@ mod tests {
             amount: Some(dec!(2.0)),
         };
 
-        transaction_processor.process_a_record(trans);
-        assert_eq!(Some(dec!(7.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().available));
-        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().held));
-        assert_eq!(Some(dec!(7.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().total));
-        assert_eq!(Some(false),Some(transaction_processor.client_accounts.get(&account_number).unwrap().locked));
+        transaction_processor.process_a_record(trans).unwrap();
+        assert_eq!(Some(dec!(7.0)),Some(transaction_processor.balance(account_number).unwrap().available));
+        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.balance(account_number).unwrap().held));
+        assert_eq!(Some(dec!(7.0)),Some(transaction_processor.balance(account_number).unwrap().total));
+        assert_eq!(Some(false),Some(transaction_processor.balance(account_number).unwrap().locked));
 
         trans = Transaction {
             transaction_type: Some(TransactionTypes::Dispute),
@@ -255,11 +256,11 @@ mod tests {
             amount: None,
         };
 
-        transaction_processor.process_a_record(trans);
-        assert_eq!(Some(dec!(2.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().available));
-        assert_eq!(Some(dec!(5.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().held));
-        assert_eq!(Some(dec!(7.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().total));
-        assert_eq!(Some(false),Some(transaction_processor.client_accounts.get(&account_number).unwrap().locked));
+        transaction_processor.process_a_record(trans).unwrap();
+        assert_eq!(Some(dec!(2.0)),Some(transaction_processor.balance(account_number).unwrap().available));
+        assert_eq!(Some(dec!(5.0)),Some(transaction_processor.balance(account_number).unwrap().held));
+        assert_eq!(Some(dec!(7.0)),Some(transaction_processor.balance(account_number).unwrap().total));
+        assert_eq!(Some(false),Some(transaction_processor.balance(account_number).unwrap().locked));
 
         trans = Transaction {
             transaction_type: Some(TransactionTypes::Chargeback),
@@ -268,11 +269,11 @@ mod tests {
             amount: None,
         };
 
-        transaction_processor.process_a_record(trans);
-        assert_eq!(Some(dec!(2.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().available));
-        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().held));
-        assert_eq!(Some(dec!(2.0)),Some(transaction_processor.client_accounts.get(&account_number).unwrap().total));
-        assert_eq!(Some(true),Some(transaction_processor.client_accounts.get(&account_number).unwrap().locked));
+        transaction_processor.process_a_record(trans).unwrap();
+        assert_eq!(Some(dec!(2.0)),Some(transaction_processor.balance(account_number).unwrap().available));
+        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.balance(account_number).unwrap().held));
+        assert_eq!(Some(dec!(2.0)),Some(transaction_processor.balance(account_number).unwrap().total));
+        assert_eq!(Some(true),Some(transaction_processor.balance(account_number).unwrap().locked));
     }
 
     #[test]
@@ -286,8 +287,8 @@ mod tests {
             amount: Some(dec!(10.0)),
         };
 
-        transaction_processor.process_a_record(trans_1);
-        assert!(transaction_processor.client_accounts.is_empty());
+        assert_eq!(Err(TransactionError::UnknownClient), transaction_processor.process_a_record(trans_1));
+        assert!(transaction_processor.accounts().next().is_none());
     }
 
     #[test]
@@ -298,11 +299,11 @@ mod tests {
             transaction_type: Some(TransactionTypes::Dispute),
             client: Some(1),
             tx: Some(1),
-            amount: Some(dec!(10.0)),
+            amount: None,
         };
 
-        transaction_processor.process_a_record(trans_1);
-        assert!(transaction_processor.client_accounts.is_empty());
+        assert_eq!(Err(TransactionError::UnknownClient), transaction_processor.process_a_record(trans_1));
+        assert!(transaction_processor.accounts().next().is_none());
     }
 
     #[test]
@@ -313,11 +314,11 @@ mod tests {
             transaction_type: Some(TransactionTypes::Chargeback),
             client: Some(1),
             tx: Some(1),
-            amount: Some(dec!(10.0)),
+            amount: None,
         };
 
-        transaction_processor.process_a_record(trans_1);
-        assert!(transaction_processor.client_accounts.is_empty());
+        assert_eq!(Err(TransactionError::UnknownClient), transaction_processor.process_a_record(trans_1));
+        assert!(transaction_processor.accounts().next().is_none());
     }
 
     #[test]
@@ -338,7 +339,7 @@ mod tests {
             amount: Some(dec!(0.0)),
         };
 
-        assert_eq!(false, Transactor::is_record_valid(&a_record));
+        assert!(!Transactor::is_record_valid(&a_record));
 
         a_record = Transaction {
             transaction_type: None,
@@ -347,7 +348,7 @@ mod tests {
             amount: Some(dec!(0.0)),
         };
 
-        assert_eq!(false, Transactor::is_record_valid(&a_record));
+        assert!(!Transactor::is_record_valid(&a_record));
     }
 
     #[test]
@@ -367,9 +368,1751 @@ mod tests {
             amount: Some(dec!(5.0)),
         };
 
-        transaction_processor.process_a_record(trans_1);
-        transaction_processor.process_a_record(trans_2);
+        transaction_processor.process_a_record(trans_1).unwrap();
+        assert_eq!(Err(TransactionError::DuplicateTx), transaction_processor.process_a_record(trans_2));
+
+        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.balance(1).unwrap().available));
+    }
+
+    #[test]
+    fn withdrawal_with_insufficient_funds_is_rejected() {
+        let mut transaction_processor = Transactor::new();
+
+        let trans_1 = Transaction {
+            transaction_type: Some(TransactionTypes::Deposit),
+            client: Some(1),
+            tx: Some(1),
+            amount: Some(dec!(10.0)),
+        };
+        let trans_2 = Transaction {
+            transaction_type: Some(TransactionTypes::Withdrawal),
+            client: Some(1),
+            tx: Some(2),
+            amount: Some(dec!(10.5)),
+        };
+
+        assert_eq!(Ok(Outcome::Deposited), transaction_processor.process_a_record(trans_1));
+        assert_eq!(Err(TransactionError::InsufficientFunds), transaction_processor.process_a_record(trans_2));
+        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.balance(1).unwrap().available));
+        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.balance(1).unwrap().total));
+    }
+
+    #[test]
+    fn incomplete_records_are_rejected() {
+        let mut transaction_processor = Transactor::new();
+
+        let trans_1 = Transaction {
+            transaction_type: Some(TransactionTypes::Deposit),
+            client: None,
+            tx: Some(1),
+            amount: Some(dec!(10.0)),
+        };
+        let trans_2 = Transaction {
+            transaction_type: Some(TransactionTypes::Deposit),
+            client: Some(1),
+            tx: Some(1),
+            amount: None,
+        };
+
+        assert_eq!(Err(TransactionError::Invalid(ValidationError::MissingClient)), transaction_processor.process_a_record(trans_1));
+        assert_eq!(Err(TransactionError::Invalid(ValidationError::MissingAmount)), transaction_processor.process_a_record(trans_2));
+        assert!(transaction_processor.accounts().next().is_none());
+    }
+
+    #[test]
+    fn dispute_rejections() {
+        let mut transaction_processor = Transactor::new();
+
+        let deposit = Transaction {
+            transaction_type: Some(TransactionTypes::Deposit),
+            client: Some(1),
+            tx: Some(1),
+            amount: Some(dec!(10.0)),
+        };
+        let dispute = Transaction {
+            transaction_type: Some(TransactionTypes::Dispute),
+            client: Some(1),
+            tx: Some(1),
+            amount: None,
+        };
+        let unknown_dispute = Transaction {
+            transaction_type: Some(TransactionTypes::Dispute),
+            client: Some(1),
+            tx: Some(2),
+            amount: None,
+        };
+        let chargeback = Transaction {
+            transaction_type: Some(TransactionTypes::Chargeback),
+            client: Some(1),
+            tx: Some(1),
+            amount: None,
+        };
+
+        assert_eq!(Ok(Outcome::Deposited), transaction_processor.process_a_record(deposit));
+        assert_eq!(Err(TransactionError::UnknownTx), transaction_processor.process_a_record(unknown_dispute));
+        assert_eq!(Err(TransactionError::NotDisputed), transaction_processor.process_a_record(chargeback));
+        assert_eq!(Ok(Outcome::Disputed), transaction_processor.process_a_record(dispute));
+        assert_eq!(Err(TransactionError::AlreadyDisputed), transaction_processor.process_a_record(dispute));
+        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.balance(1).unwrap().available));
+        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.balance(1).unwrap().held));
+        assert_eq!(Ok(Outcome::ChargedBack), transaction_processor.process_a_record(chargeback));
+    }
+
+    fn locked_account(lock_policy: LockPolicy) -> Transactor {
+        let mut transaction_processor = Transactor::new().with_lock_policy(lock_policy);
+
+        let records = [
+            Transaction {
+                transaction_type: Some(TransactionTypes::Deposit),
+                client: Some(1),
+                tx: Some(1),
+                amount: Some(dec!(10.0)),
+            },
+            Transaction {
+                transaction_type: Some(TransactionTypes::Deposit),
+                client: Some(1),
+                tx: Some(2),
+                amount: Some(dec!(5.0)),
+            },
+            Transaction {
+                transaction_type: Some(TransactionTypes::Dispute),
+                client: Some(1),
+                tx: Some(1),
+                amount: None,
+            },
+            Transaction {
+                transaction_type: Some(TransactionTypes::Chargeback),
+                client: Some(1),
+                tx: Some(1),
+                amount: None,
+            },
+        ];
+
+        for record in records {
+            transaction_processor.process_a_record(record).unwrap();
+        }
+        assert!(transaction_processor.balance(1).unwrap().locked);
+
+        transaction_processor
+    }
+
+    #[test]
+    fn locked_account_rejects_deposits_and_withdrawals() {
+        let mut transaction_processor = locked_account(LockPolicy::default());
+
+        let deposit = Transaction {
+            transaction_type: Some(TransactionTypes::Deposit),
+            client: Some(1),
+            tx: Some(3),
+            amount: Some(dec!(1.0)),
+        };
+        let withdrawal = Transaction {
+            transaction_type: Some(TransactionTypes::Withdrawal),
+            client: Some(1),
+            tx: Some(4),
+            amount: Some(dec!(1.0)),
+        };
+        let dispute = Transaction {
+            transaction_type: Some(TransactionTypes::Dispute),
+            client: Some(1),
+            tx: Some(2),
+            amount: None,
+        };
+
+        assert_eq!(Err(TransactionError::AccountLocked), transaction_processor.process_a_record(deposit));
+        assert_eq!(Err(TransactionError::AccountLocked), transaction_processor.process_a_record(withdrawal));
+        assert_eq!(Ok(Outcome::Disputed), transaction_processor.process_a_record(dispute));
+        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.balance(1).unwrap().available));
+        assert_eq!(Some(dec!(5.0)),Some(transaction_processor.balance(1).unwrap().total));
+    }
+
+    #[test]
+    fn locked_account_is_reported_before_a_duplicate_tx() {
+        let deposit = Transaction {
+            transaction_type: Some(TransactionTypes::Deposit),
+            client: Some(1),
+            tx: Some(1),
+            amount: Some(dec!(1.0)),
+        };
+        let withdrawal = Transaction {
+            transaction_type: Some(TransactionTypes::Withdrawal),
+            ..deposit
+        };
+
+        // Deposits and withdrawals check the lock first, whatever the tx
+        let mut transaction_processor = locked_account(LockPolicy::default());
+        assert_eq!(Err(TransactionError::AccountLocked), transaction_processor.process_a_record(deposit));
+        assert_eq!(Err(TransactionError::AccountLocked), transaction_processor.process_a_record(withdrawal));
+
+        // A client without an account has no lock, nor does an ignored one
+        assert_eq!(Err(TransactionError::DuplicateTx), transaction_processor.process_a_record(Transaction { client: Some(2), ..deposit }));
+        let mut transaction_processor = locked_account(LockPolicy::Ignore);
+        assert_eq!(Err(TransactionError::DuplicateTx), transaction_processor.process_a_record(deposit));
+        assert_eq!(Err(TransactionError::DuplicateTx), transaction_processor.process_a_record(withdrawal));
+    }
+
+    #[test]
+    fn freeze_all_rejects_new_disputes() {
+        let mut transaction_processor = locked_account(LockPolicy::FreezeAll);
+
+        let dispute = Transaction {
+            transaction_type: Some(TransactionTypes::Dispute),
+            client: Some(1),
+            tx: Some(2),
+            amount: None,
+        };
+
+        assert_eq!(Err(TransactionError::AccountLocked), transaction_processor.process_a_record(dispute));
+        assert_eq!(Some(dec!(5.0)),Some(transaction_processor.balance(1).unwrap().available));
+    }
+
+    #[test]
+    fn ignore_lock_policy_allows_transactions() {
+        let mut transaction_processor = locked_account(LockPolicy::Ignore);
+
+        let deposit = Transaction {
+            transaction_type: Some(TransactionTypes::Deposit),
+            client: Some(1),
+            tx: Some(3),
+            amount: Some(dec!(1.0)),
+        };
+
+        assert_eq!(Ok(Outcome::Deposited), transaction_processor.process_a_record(deposit));
+        assert_eq!(Some(dec!(6.0)),Some(transaction_processor.balance(1).unwrap().available));
+    }
+
+    #[test]
+    fn unlock_is_recorded_and_reopens_account() {
+        let mut transaction_processor = locked_account(LockPolicy::default());
+
+        assert_eq!(Err(TransactionError::UnknownClient), transaction_processor.unlock(2, "admin", "typo"));
+        assert_eq!(Ok(Outcome::Unlocked), transaction_processor.unlock(1, "admin", "chargeback reversed"));
+        assert_eq!(Err(TransactionError::NotLocked), transaction_processor.unlock(1, "admin", "again"));
+
+        let account = transaction_processor.account(1).unwrap();
+        assert!(!account.locked());
+        assert_eq!(
+            vec![Unlock {
+                by: "admin".to_string(),
+                reason: "chargeback reversed".to_string(),
+            }],
+            account.unlocks()
+        );
+
+        let deposit = Transaction {
+            transaction_type: Some(TransactionTypes::Deposit),
+            client: Some(1),
+            tx: Some(3),
+            amount: Some(dec!(1.0)),
+        };
+
+        assert_eq!(Ok(Outcome::Deposited), transaction_processor.process_a_record(deposit));
+    }
+
+    fn disputed_withdrawal(dispute_policy: DisputePolicy) -> Transactor {
+        let mut transaction_processor = Transactor::new().with_dispute_policy(dispute_policy);
+
+        let records = [
+            Transaction {
+                transaction_type: Some(TransactionTypes::Deposit),
+                client: Some(1),
+                tx: Some(1),
+                amount: Some(dec!(10.0)),
+            },
+            Transaction {
+                transaction_type: Some(TransactionTypes::Withdrawal),
+                client: Some(1),
+                tx: Some(2),
+                amount: Some(dec!(4.0)),
+            },
+            Transaction {
+                transaction_type: Some(TransactionTypes::Dispute),
+                client: Some(1),
+                tx: Some(2),
+                amount: None,
+            },
+        ];
+
+        for record in records {
+            transaction_processor.process_a_record(record).unwrap();
+        }
+
+        transaction_processor
+    }
+
+    #[test]
+    fn dispute_withdrawal_then_resolve_outcome() {
+        let mut transaction_processor = disputed_withdrawal(DisputePolicy::DirectionAware);
+        assert_eq!(Some(dec!(6.0)),Some(transaction_processor.balance(1).unwrap().available));
+        assert_eq!(Some(dec!(4.0)),Some(transaction_processor.balance(1).unwrap().held));
+        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.balance(1).unwrap().total));
+
+        let trans = Transaction {
+            transaction_type: Some(TransactionTypes::Resolve),
+            client: Some(1),
+            tx: Some(2),
+            amount: None,
+        };
+
+        transaction_processor.process_a_record(trans).unwrap();
+        assert_eq!(Some(dec!(6.0)),Some(transaction_processor.balance(1).unwrap().available));
+        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.balance(1).unwrap().held));
+        assert_eq!(Some(dec!(6.0)),Some(transaction_processor.balance(1).unwrap().total));
+        assert_eq!(Some(false),Some(transaction_processor.balance(1).unwrap().locked));
+    }
+
+    #[test]
+    fn dispute_withdrawal_then_chargeback_outcome() {
+        let mut transaction_processor = disputed_withdrawal(DisputePolicy::DirectionAware);
+
+        let trans = Transaction {
+            transaction_type: Some(TransactionTypes::Chargeback),
+            client: Some(1),
+            tx: Some(2),
+            amount: None,
+        };
+
+        transaction_processor.process_a_record(trans).unwrap();
+        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.balance(1).unwrap().available));
+        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.balance(1).unwrap().held));
+        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.balance(1).unwrap().total));
+        assert_eq!(Some(true),Some(transaction_processor.balance(1).unwrap().locked));
+    }
+
+    #[test]
+    fn uniform_dispute_policy_treats_withdrawal_as_deposit() {
+        let mut transaction_processor = disputed_withdrawal(DisputePolicy::Uniform);
+        assert_eq!(Some(dec!(2.0)),Some(transaction_processor.balance(1).unwrap().available));
+        assert_eq!(Some(dec!(4.0)),Some(transaction_processor.balance(1).unwrap().held));
+        assert_eq!(Some(dec!(6.0)),Some(transaction_processor.balance(1).unwrap().total));
+
+        let trans = Transaction {
+            transaction_type: Some(TransactionTypes::Chargeback),
+            client: Some(1),
+            tx: Some(2),
+            amount: None,
+        };
+
+        transaction_processor.process_a_record(trans).unwrap();
+        assert_eq!(Some(dec!(2.0)),Some(transaction_processor.balance(1).unwrap().available));
+        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.balance(1).unwrap().held));
+        assert_eq!(Some(dec!(2.0)),Some(transaction_processor.balance(1).unwrap().total));
+    }
+
+    #[test]
+    fn dispute_state_transitions() {
+        assert_eq!(Ok(DisputeState::Disputed), DisputeState::Processed.transition(TransactionTypes::Dispute));
+        assert_eq!(Err(TransactionError::NotDisputed), DisputeState::Processed.transition(TransactionTypes::Resolve));
+        assert_eq!(Err(TransactionError::NotDisputed), DisputeState::Processed.transition(TransactionTypes::Chargeback));
+        assert_eq!(Err(TransactionError::AlreadyDisputed), DisputeState::Disputed.transition(TransactionTypes::Dispute));
+        assert_eq!(Ok(DisputeState::Resolved), DisputeState::Disputed.transition(TransactionTypes::Resolve));
+        assert_eq!(Ok(DisputeState::ChargedBack), DisputeState::Disputed.transition(TransactionTypes::Chargeback));
+        assert_eq!(Err(TransactionError::DisputeClosed), DisputeState::Resolved.transition(TransactionTypes::Dispute));
+        assert_eq!(Err(TransactionError::DisputeClosed), DisputeState::ChargedBack.transition(TransactionTypes::Resolve));
+        assert_eq!(Err(TransactionError::DuplicateTx), DisputeState::Processed.transition(TransactionTypes::Deposit));
+    }
+
+    #[test]
+    fn resolved_transaction_cannot_be_disputed_again() {
+        let mut transaction_processor = Transactor::new();
+
+        let deposit = Transaction {
+            transaction_type: Some(TransactionTypes::Deposit),
+            client: Some(1),
+            tx: Some(1),
+            amount: Some(dec!(10.0)),
+        };
+        let dispute = Transaction {
+            transaction_type: Some(TransactionTypes::Dispute),
+            client: Some(1),
+            tx: Some(1),
+            amount: None,
+        };
+        let resolve = Transaction {
+            transaction_type: Some(TransactionTypes::Resolve),
+            client: Some(1),
+            tx: Some(1),
+            amount: None,
+        };
+
+        transaction_processor.process_a_record(deposit).unwrap();
+        transaction_processor.process_a_record(dispute).unwrap();
+        assert_eq!(vec![1], transaction_processor.open_disputes(1));
+        transaction_processor.process_a_record(resolve).unwrap();
+
+        assert_eq!(Err(TransactionError::DisputeClosed), transaction_processor.process_a_record(dispute));
+        assert_eq!(Err(TransactionError::DisputeClosed), transaction_processor.process_a_record(resolve));
+        assert_eq!(
+            DisputeState::Resolved,
+            transaction_processor.transaction(1, 1).unwrap().unwrap().state
+        );
+        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.balance(1).unwrap().available));
+        assert_eq!(Some(dec!(0.0)),Some(transaction_processor.balance(1).unwrap().held));
+    }
+
+    #[test]
+    fn charged_back_transaction_cannot_be_resolved() {
+        let mut transaction_processor = locked_account(LockPolicy::default());
+
+        let resolve = Transaction {
+            transaction_type: Some(TransactionTypes::Resolve),
+            client: Some(1),
+            tx: Some(1),
+            amount: None,
+        };
+
+        assert_eq!(Err(TransactionError::DisputeClosed), transaction_processor.process_a_record(resolve));
+        assert_eq!(
+            DisputeState::ChargedBack,
+            transaction_processor.transaction(1, 1).unwrap().unwrap().state
+        );
+        assert_eq!(Some(dec!(5.0)),Some(transaction_processor.balance(1).unwrap().total));
+    }
+
+    #[test]
+    fn rejections_are_written_as_json_lines() {
+        let record = Transaction {
+            transaction_type: Some(TransactionTypes::Withdrawal),
+            client: Some(2),
+            tx: Some(5),
+            amount: Some(dec!(3.5)),
+        };
+        let rejection = Rejection::new(6, &record, TransactionError::InsufficientFunds);
+
+        let mut buffer = Vec::new();
+        let mut json_lines = RejectWriter::new(RejectFormat::JsonLines, &mut buffer);
+        json_lines.write(&rejection).unwrap();
+        json_lines.flush().unwrap();
+        drop(json_lines);
+
+        assert_eq!(
+            "{\"source\":null,\"line\":6,\"type\":\"withdrawal\",\"client\":2,\"tx\":5,\"amount\":\"3.5\",\"reason\":\"insufficient available funds\"}\n",
+            String::from_utf8(buffer).unwrap()
+        );
+    }
+
+    #[test]
+    fn reject_csv_has_header_and_reason() {
+        let record = Transaction {
+            transaction_type: Some(TransactionTypes::Deposit),
+            client: None,
+            tx: Some(6),
+            amount: Some(dec!(1.0)),
+        };
+
+        let mut buffer = Vec::new();
+        let mut writer = RejectWriter::new(RejectFormat::Csv, &mut buffer);
+        writer.write(&Rejection::new(8, &record, TransactionError::Invalid(ValidationError::MissingClient))).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        assert_eq!(
+            "source,line,type,client,tx,amount,reason\n,8,deposit,,6,1.0,record is missing its client\n",
+            String::from_utf8(buffer).unwrap()
+        );
+    }
+
+    #[test]
+    fn reject_format_from_path() {
+        assert_eq!(Ok(RejectFormat::Csv), RejectFormat::from_path("rejects.csv"));
+        assert_eq!(Ok(RejectFormat::JsonLines), RejectFormat::from_path("rejects.jsonl"));
+        assert_eq!(Ok(RejectFormat::JsonLines), RejectFormat::from_path("rejects.ndjson"));
+        assert_eq!(Ok(RejectFormat::Csv), RejectFormat::from_path("rejects"));
+        assert!(RejectFormat::from_path("rejects.json").is_err());
+    }
+
+    #[tokio::test]
+    async fn malformed_rows_are_rejected_and_skipped() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 1.0\n\
+                     deposit, 1, 2, abc\n\
+                     deposit, 1, 3, 2.0, extra\n\
+                     deposit, , 4, 2.0\n\
+                     withdrawal, 1, 5, 0.5\n";
+        let (tx, mut rx) = tokio::sync::mpsc::channel(32);
+
+        read_csv(input.as_bytes(), tx, false).await.unwrap();
+
+        let mut received = Vec::new();
+        while let Some(ingested) = rx.recv().await {
+            received.push(ingested);
+        }
+
+        assert_eq!(5, received.len());
+        assert!(matches!(received[0], Ingested::Record { line: 2, .. }));
+        assert!(matches!(&received[1], Ingested::Rejected(Rejection { line: 3, tx: None, .. })));
+        assert!(matches!(&received[2], Ingested::Rejected(Rejection { line: 4, tx: None, .. })));
+        assert!(matches!(&received[3], Ingested::Rejected(Rejection { line: 5, tx: Some(4), .. })));
+        assert!(matches!(received[4], Ingested::Record { line: 6, .. }));
+    }
+
+    #[tokio::test]
+    async fn strict_mode_stops_at_malformed_row() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 1.0\n\
+                     deposit, 1, 2, 2.0, extra\n\
+                     deposit, 1, 3, 1.0\n";
+        let (tx, mut rx) = tokio::sync::mpsc::channel(32);
+
+        let result = read_csv(input.as_bytes(), tx, true).await;
+
+        assert!(matches!(result, Err(IngestError::Malformed { line: 3, .. })));
+        assert!(matches!(rx.recv().await, Some(Ingested::Record { line: 2, .. })));
+        assert_eq!(None, rx.recv().await);
+    }
+
+    #[test]
+    fn accounts_are_listed_in_a_stable_order() {
+        let mut transaction_processor = Transactor::new();
+
+        for (client, tx, amount) in [(3, 1, dec!(5.0)), (1, 2, dec!(20.0)), (2, 3, dec!(5.0)), (4, 4, dec!(1.0))] {
+            let trans = Transaction {
+                transaction_type: Some(TransactionTypes::Deposit),
+                client: Some(client),
+                tx: Some(tx),
+                amount: Some(amount),
+            };
+            transaction_processor.process_a_record(trans).unwrap();
+        }
+
+        let clients = |sort_by| {
+            transaction_processor
+                .accounts_sorted_by(sort_by)
+                .into_iter()
+                .map(|(client, _)| client)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec![1, 2, 3, 4], clients(SortKey::Client));
+        assert_eq!(vec![4, 2, 3, 1], clients(SortKey::Available));
+        assert_eq!(vec![4, 2, 3, 1], clients(SortKey::Total));
+        assert_eq!(vec![1, 2, 3, 4], clients(SortKey::Held));
+    }
+
+    #[test]
+    fn sort_key_from_str() {
+        assert_eq!(Ok(SortKey::Client), "client".parse());
+        assert_eq!(Ok(SortKey::Total), "total".parse());
+        assert!("balance".parse::<SortKey>().is_err());
+    }
+
+    fn two_accounts() -> Transactor {
+        let mut transaction_processor = Transactor::new();
+
+        for (client, tx, amount) in [(2, 1, dec!(2.0)), (1, 2, dec!(1.2346))] {
+            let trans = Transaction {
+                transaction_type: Some(TransactionTypes::Deposit),
+                client: Some(client),
+                tx: Some(tx),
+                amount: Some(amount),
+            };
+            transaction_processor.process_a_record(trans).unwrap();
+        }
+
+        transaction_processor
+    }
+
+    fn written(transaction_processor: &Transactor, format: OutputFormat) -> String {
+        let mut buffer = Vec::new();
+        transaction_processor.write_accounts(format, &mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn write_accounts_as_csv() {
+        assert_eq!(
+            "client,available,held,total,locked\n1,1.2346,0,1.2346,false\n2,2,0,2,false\n",
+            written(&two_accounts(), OutputFormat::Csv)
+        );
+        assert_eq!(
+            "client,available,held,total,locked\n",
+            written(&Transactor::new(), OutputFormat::Csv)
+        );
+    }
+
+    #[test]
+    fn write_accounts_as_json_and_json_lines() {
+        let json: serde_json::Value = serde_json::from_str(&written(&two_accounts(), OutputFormat::Json)).unwrap();
+        assert_eq!(
+            serde_json::json!([
+                {"client": 1, "available": "1.2346", "held": "0", "total": "1.2346", "locked": false},
+                {"client": 2, "available": "2", "held": "0", "total": "2", "locked": false},
+            ]),
+            json
+        );
+
+        assert_eq!(
+            "{\"client\":1,\"available\":\"1.2346\",\"held\":\"0\",\"total\":\"1.2346\",\"locked\":false}\n\
+             {\"client\":2,\"available\":\"2\",\"held\":\"0\",\"total\":\"2\",\"locked\":false}\n",
+            written(&two_accounts(), OutputFormat::JsonLines)
+        );
+    }
+
+    #[test]
+    fn write_accounts_as_table() {
+        let expected = [
+            "client | available | held |  total | locked",
+            "-------+-----------+------+--------+-------",
+            "     1 |    1.2346 |    0 | 1.2346 |  false",
+            "     2 |         2 |    0 |      2 |  false",
+        ];
+
+        assert_eq!(expected.join("\n") + "\n", written(&two_accounts(), OutputFormat::Table));
+    }
+
+    #[test]
+    fn read_api_returns_snapshots() {
+        let mut transaction_processor = two_accounts();
+
+        let dispute = Transaction {
+            transaction_type: Some(TransactionTypes::Dispute),
+            client: Some(2),
+            tx: Some(1),
+            amount: None,
+        };
+
+        let before = transaction_processor.balance(2).unwrap();
+        transaction_processor.process_a_record(dispute).unwrap();
+
+        assert_eq!(
+            Balance {
+                available: dec!(2.0),
+                held: dec!(0.0),
+                total: dec!(2.0),
+                locked: false,
+            },
+            before
+        );
+        assert_eq!(Some(dec!(2.0)), Some(transaction_processor.balance(2).unwrap().held));
+        assert_eq!(vec![1, 2], transaction_processor.accounts().map(|(client, _)| client).collect::<Vec<_>>());
+        assert_eq!(vec![1], transaction_processor.open_disputes(2));
+        assert!(transaction_processor.open_disputes(3).is_empty());
+        assert_eq!(None, transaction_processor.balance(3));
+
+        let entry = transaction_processor.transaction(2, 1).unwrap().unwrap();
+        assert_eq!(DisputeState::Disputed, entry.state);
+        assert_eq!((EntryKind::Deposit, 2, dec!(2.0)), (entry.kind, entry.client, entry.amount));
+        assert!(transaction_processor.transaction(1, 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn tx_ids_are_unique_across_clients() {
+        let mut transaction_processor = two_accounts();
+
+        let deposit = Transaction {
+            transaction_type: Some(TransactionTypes::Deposit),
+            client: Some(3),
+            tx: Some(1),
+            amount: Some(dec!(7.0)),
+        };
+        let withdrawal = Transaction {
+            transaction_type: Some(TransactionTypes::Withdrawal),
+            client: Some(1),
+            tx: Some(1),
+            amount: Some(dec!(1.0)),
+        };
+
+        assert_eq!(Err(TransactionError::DuplicateTx), transaction_processor.process_a_record(deposit));
+        assert_eq!(Err(TransactionError::DuplicateTx), transaction_processor.process_a_record(withdrawal));
+        assert_eq!(None, transaction_processor.balance(3));
+        assert_eq!(Some(dec!(1.2346)), Some(transaction_processor.balance(1).unwrap().available));
+    }
+
+    #[test]
+    fn dispute_from_another_client_is_rejected() {
+        let mut transaction_processor = two_accounts();
+
+        for transaction_type in [TransactionTypes::Dispute, TransactionTypes::Resolve, TransactionTypes::Chargeback] {
+            let trans = Transaction {
+                transaction_type: Some(transaction_type),
+                client: Some(1),
+                tx: Some(1),
+                amount: None,
+            };
+
+            assert_eq!(Err(TransactionError::ClientMismatch), transaction_processor.process_a_record(trans));
+        }
+
+        assert_eq!(DisputeState::Processed, transaction_processor.transaction(2, 1).unwrap().unwrap().state);
+        assert!(transaction_processor.transaction(1, 1).unwrap().is_none());
+        assert_eq!(Some(dec!(2.0)), Some(transaction_processor.balance(2).unwrap().available));
+        assert_eq!(Some(dec!(1.2346)), Some(transaction_processor.balance(1).unwrap().available));
+    }
+
+    /// Fails every write, to check that a storage failure leaves accounts untouched.
+    /// Fails every change of dispute state, and every insert unless
+    /// `inserts` is set.
+    struct FailingLedger {
+        ledger: MemoryLedger,
+        inserts: bool,
+    }
+
+    impl LedgerStore for FailingLedger {
+        fn get(&self, tx: u32) -> Result<Option<LedgerEntry>, StoreError> {
+            self.ledger.get(tx)
+        }
+
+        fn insert(&mut self, tx: u32, entry: LedgerEntry) -> Result<(), StoreError> {
+            if self.inserts {
+                return self.ledger.insert(tx, entry);
+            }
+            Err(StoreError("disk full".to_string()))
+        }
+
+        fn set_state(&mut self, _tx: u32, _state: DisputeState) -> Result<(), StoreError> {
+            Err(StoreError("disk full".to_string()))
+        }
+
+        fn entries(&self) -> Result<Entries<'_>, StoreError> {
+            self.ledger.entries()
+        }
+    }
+
+    #[test]
+    fn storage_failure_leaves_account_untouched() {
+        let mut ledger = MemoryLedger::default();
+        let deposit = Transaction {
+            transaction_type: Some(TransactionTypes::Deposit),
+            client: Some(1),
+            tx: Some(1),
+            amount: Some(dec!(5.0)),
+        };
+        ledger.insert(1, LedgerEntry::new(EntryKind::Deposit, 1, 1, dec!(5.0))).unwrap();
+
+        let mut transaction_processor = Transactor::with_ledger(FailingLedger { ledger, inserts: false });
+
+        let result = transaction_processor.process_a_record(Transaction { tx: Some(2), ..deposit });
+        assert_eq!(Err(TransactionError::Storage("disk full".to_string())), result);
+        assert_eq!(None, transaction_processor.balance(1));
+
+        let dispute = Transaction {
+            transaction_type: Some(TransactionTypes::Dispute),
+            client: Some(1),
+            tx: Some(1),
+            amount: None,
+        };
+        assert_eq!(Err(TransactionError::UnknownClient), transaction_processor.process_a_record(dispute));
+    }
+
+    #[test]
+    fn storage_failure_leaves_dispute_state_untouched() {
+        let mut transaction_processor = Transactor::with_ledger(FailingLedger { ledger: MemoryLedger::default(), inserts: true });
+        let deposit = Transaction {
+            transaction_type: Some(TransactionTypes::Deposit),
+            client: Some(1),
+            tx: Some(1),
+            amount: Some(dec!(5.0)),
+        };
+        let dispute = Transaction {
+            transaction_type: Some(TransactionTypes::Dispute),
+            amount: None,
+            ..deposit
+        };
+        assert_eq!(Ok(Outcome::Deposited), transaction_processor.process_a_record(deposit));
+
+        let result = transaction_processor.process_a_record(dispute);
+        assert_eq!(Err(TransactionError::Storage("disk full".to_string())), result);
+        let account = transaction_processor.balance(1).unwrap();
+        assert_eq!((dec!(5.0), dec!(0), dec!(5.0)), (account.available, account.held, account.total));
+        assert!(transaction_processor.open_disputes(1).is_empty());
+        assert_eq!(Some(DisputeState::Processed), transaction_processor.transaction(1, 1).unwrap().map(|entry| entry.state));
+    }
+
+    #[cfg(feature = "disk_ledger")]
+    #[test]
+    fn disk_ledger_keeps_entries_and_dispute_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger.redb");
+
+        let mut transaction_processor = Transactor::with_ledger(DiskLedger::open(&path).unwrap());
+        let deposit = Transaction {
+            transaction_type: Some(TransactionTypes::Deposit),
+            client: Some(1),
+            tx: Some(1),
+            amount: Some(dec!(1.2345)),
+        };
+        let withdrawal = Transaction {
+            transaction_type: Some(TransactionTypes::Withdrawal),
+            client: Some(1),
+            tx: Some(2),
+            amount: Some(dec!(0.5)),
+        };
+        let dispute = Transaction {
+            transaction_type: Some(TransactionTypes::Dispute),
+            client: Some(1),
+            tx: Some(2),
+            amount: None,
+        };
+
+        assert_eq!(Ok(Outcome::Deposited), transaction_processor.process_a_record(deposit));
+        assert_eq!(Ok(Outcome::Withdrawn), transaction_processor.process_a_record(withdrawal));
+        assert_eq!(Ok(Outcome::Disputed), transaction_processor.process_a_record(dispute));
+        assert_eq!(Err(TransactionError::DuplicateTx), transaction_processor.process_a_record(deposit));
+        assert_eq!(Err(TransactionError::AlreadyDisputed), transaction_processor.process_a_record(dispute));
+        transaction_processor.flush_ledger().unwrap();
+        drop(transaction_processor);
+
+        // The entries outlive the transactor which wrote them
+        let ledger = DiskLedger::open(&path).unwrap();
+        assert_eq!(Some(LedgerEntry::new(EntryKind::Deposit, 1, 1, dec!(1.2345))), ledger.get(1).unwrap());
+        assert_eq!(Some(LedgerEntry { state: DisputeState::Disputed, ..LedgerEntry::new(EntryKind::Withdrawal, 1, 2, dec!(0.5)) }), ledger.get(2).unwrap());
+        assert_eq!(None, ledger.get(3).unwrap());
+    }
+
+    fn client_entries_are_listed_by_client<L: LedgerStore>(mut ledger: L) {
+        ledger.insert(3, LedgerEntry::new(EntryKind::Deposit, 2, 3, dec!(3))).unwrap();
+        ledger.insert(1, LedgerEntry::new(EntryKind::Deposit, 1, 1, dec!(1))).unwrap();
+        ledger.insert(2, LedgerEntry::new(EntryKind::Withdrawal, 2, 2, dec!(2))).unwrap();
+        ledger.set_state(3, DisputeState::Disputed).unwrap();
+
+        let entries: Vec<_> = ledger.client_entries(2).unwrap().map(Result::unwrap).collect();
+        assert_eq!(vec![(2, LedgerEntry::new(EntryKind::Withdrawal, 2, 2, dec!(2))), (3, LedgerEntry { state: DisputeState::Disputed, ..LedgerEntry::new(EntryKind::Deposit, 2, 3, dec!(3)) })], entries);
+        assert_eq!(1, ledger.client_entries(1).unwrap().count());
+        assert_eq!(0, ledger.client_entries(3).unwrap().count());
+    }
+
+    #[test]
+    fn memory_ledger_lists_a_clients_entries() {
+        client_entries_are_listed_by_client(MemoryLedger::default());
+    }
+
+    #[cfg(feature = "disk_ledger")]
+    #[test]
+    fn disk_ledger_lists_a_clients_entries_from_its_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger.redb");
+
+        // The writes are not yet committed, so they are read from the pending ones
+        client_entries_are_listed_by_client(DiskLedger::open(&path).unwrap());
+
+        // and the index is kept along with the entries
+        let ledger = DiskLedger::open(&path).unwrap();
+        assert_eq!(vec![2, 3], ledger.client_entries(2).unwrap().map(|entry| entry.unwrap().0).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn snapshot_restores_accounts_ledger_and_disputes() {
+        let mut transaction_processor = two_accounts();
+
+        for (transaction_type, tx) in [(TransactionTypes::Dispute, 2), (TransactionTypes::Chargeback, 2)] {
+            let trans = Transaction {
+                transaction_type: Some(transaction_type),
+                client: Some(1),
+                tx: Some(tx),
+                amount: None,
+            };
+            transaction_processor.process_a_record(trans).unwrap();
+        }
+        transaction_processor.unlock(1, "admin", "chargeback reversed").unwrap();
+        let dispute = Transaction {
+            transaction_type: Some(TransactionTypes::Dispute),
+            client: Some(2),
+            tx: Some(1),
+            amount: None,
+        };
+        transaction_processor.process_a_record(dispute).unwrap();
+
+        let mut snapshot = Vec::new();
+        transaction_processor.save_snapshot(&mut snapshot).unwrap();
+
+        let mut restored = Transactor::new();
+        restored.restore_snapshot(snapshot.as_slice()).unwrap();
+
+        assert_eq!(transaction_processor.balance(1), restored.balance(1));
+        assert_eq!(transaction_processor.balance(2), restored.balance(2));
+        assert_eq!(vec![1], restored.open_disputes(2));
+        assert_eq!(transaction_processor.account(1).unwrap().unlocks(), restored.account(1).unwrap().unlocks());
+        assert_eq!(transaction_processor.transaction(1, 2).unwrap(), restored.transaction(1, 2).unwrap());
+        assert_eq!(DisputeState::ChargedBack, restored.transaction(1, 2).unwrap().unwrap().state);
+
+        // Processing carries on from the restored state
+        let resolve = Transaction {
+            transaction_type: Some(TransactionTypes::Resolve),
+            client: Some(2),
+            tx: Some(1),
+            amount: None,
+        };
+        let deposit = Transaction {
+            transaction_type: Some(TransactionTypes::Deposit),
+            client: Some(3),
+            tx: Some(2),
+            amount: Some(dec!(1.0)),
+        };
+        assert_eq!(Ok(Outcome::Resolved), restored.process_a_record(resolve));
+        assert_eq!(Err(TransactionError::DuplicateTx), restored.process_a_record(deposit));
+        assert_eq!(Some(dec!(2.0)), Some(restored.balance(2).unwrap().available));
+    }
+
+    #[test]
+    fn snapshot_of_another_version_is_refused() {
+        let snapshot = r#"{"version":0,"accounts":[],"ledger":[]}"#;
+        let result = Transactor::new().restore_snapshot(snapshot.as_bytes());
+        assert!(matches!(result, Err(SnapshotError::UnsupportedVersion(0))));
+
+        let result = Transactor::new().restore_snapshot(r#"{"version":1}"#.as_bytes());
+        assert!(matches!(result, Err(SnapshotError::Format(_))));
+
+        // The version is checked before anything is restored
+        let result = Transactor::new().restore_snapshot(r#"{"accounts":[],"ledger":[],"version":1}"#.as_bytes());
+        assert!(matches!(result, Err(SnapshotError::Format(_))));
+
+        // Entries are stored as they are read, so a failing store is reported as such
+        let snapshot = r#"{"version":1,"accounts":[],"ledger":[{"tx":1,"type":"deposit","client":1,"amount":"1","state":"processed"}]}"#;
+        let result = Transactor::with_ledger(FailingLedger { ledger: MemoryLedger::default(), inserts: false }).restore_snapshot(snapshot.as_bytes());
+        assert!(matches!(result, Err(SnapshotError::Storage(StoreError(e))) if e == "disk full"));
+    }
+
+    #[test]
+    fn wal_replays_accepted_records_and_drops_a_torn_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.jsonl");
+
+        let mut transaction_processor = Transactor::new();
+        let (mut wal, replayed) = WriteAheadLog::recover(&path, FsyncPolicy::Always, &mut transaction_processor, 0).unwrap();
+        assert_eq!(0, replayed);
+
+        let records = [
+            (TransactionTypes::Deposit, 1, Some(dec!(3.1415))),
+            (TransactionTypes::Withdrawal, 2, Some(dec!(1.0))),
+            (TransactionTypes::Dispute, 1, None),
+        ];
+        for (line, (transaction_type, tx, amount)) in (2..).zip(records) {
+            let trans = Transaction {
+                transaction_type: Some(transaction_type),
+                client: Some(1),
+                tx: Some(tx),
+                amount,
+            };
+            transaction_processor.process_a_record(trans).unwrap();
+            wal.append(Some("in.csv"), line, Some(&trans)).unwrap();
+        }
+        drop(wal);
+
+        // A crash part way through writing a record
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        std::io::Write::write_all(&mut file, br#"{"type":"deposit","cli"#).unwrap();
+        drop(file);
+
+        let mut recovered = Transactor::new();
+        let (mut wal, replayed) = WriteAheadLog::recover(&path, FsyncPolicy::Never, &mut recovered, 0).unwrap();
+        assert_eq!(3, replayed);
+        assert_eq!(transaction_processor.balance(1), recovered.balance(1));
+        assert_eq!(vec![1], recovered.open_disputes(1));
+
+        let resolve = Transaction {
+            transaction_type: Some(TransactionTypes::Resolve),
+            client: Some(1),
+            tx: Some(1),
+            amount: None,
+        };
+        recovered.process_a_record(resolve).unwrap();
+        wal.append(None, 0, Some(&resolve)).unwrap();
+        wal.sync().unwrap();
+        drop(wal);
+
+        let mut recovered_again = Transactor::new();
+        let (_, replayed) = WriteAheadLog::recover(&path, FsyncPolicy::Always, &mut recovered_again, 0).unwrap();
+        assert_eq!(4, replayed);
+        assert_eq!(Some(dec!(2.1415)), Some(recovered_again.balance(1).unwrap().available));
+        assert!(recovered_again.open_disputes(1).is_empty());
+    }
+
+    #[test]
+    fn wal_with_a_corrupt_record_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.jsonl");
+        std::fs::write(&path, "{\"seq\":1,\"line\":2,\"record\":{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1\"}}\nnot json\n").unwrap();
+
+        let result = WriteAheadLog::recover(&path, FsyncPolicy::Always, &mut Transactor::new(), 0);
+        assert!(matches!(result, Err(WalError::Corrupt { line: 2, .. })));
+
+        assert_eq!(Ok(FsyncPolicy::Every(100)), "100".parse());
+        assert_eq!(Ok(FsyncPolicy::Never), "never".parse());
+        assert!("0".parse::<FsyncPolicy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn sources_tag_their_records_and_rejections() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.csv");
+        std::fs::write(&path, "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, , 2, 1.0\n").unwrap();
+        let source = Source::Path(path.clone());
+        let (tx, mut rx) = tokio::sync::mpsc::channel(32);
+
+        read_source(&source, InputFormat::Auto, tx, false).await.unwrap();
+
+        let name = path.display().to_string();
+        match rx.recv().await {
+            Some(Ingested::Record { source: Some(source), line: 2, .. }) => assert_eq!(name, &*source),
+            other => panic!("expected a record, got {other:?}"),
+        }
+        match rx.recv().await {
+            Some(Ingested::Rejected(rejection)) => assert_eq!(Some(name), rejection.source),
+            other => panic!("expected a rejection, got {other:?}"),
+        }
+        assert_eq!(None, rx.recv().await);
+
+        let missing = Source::Path(dir.path().join("missing.csv"));
+        let (tx, _rx) = tokio::sync::mpsc::channel(32);
+        assert!(matches!(read_source(&missing, InputFormat::Auto, tx, false).await, Err(IngestError::Io(_))));
+    }
+
+    #[test]
+    fn sources_and_orderings_are_parsed() {
+        assert_eq!(Ok(Source::Stdin), "-".parse());
+        assert_eq!(Ok(Source::Path("in.csv".into())), "in.csv".parse());
+        assert_eq!(Ok(InputOrdering::Sequential), "sequential".parse());
+        assert_eq!(Ok(InputOrdering::Interleaved), "interleaved".parse());
+        assert!("random".parse::<InputOrdering>().is_err());
+
+        let record = Transaction {
+            transaction_type: Some(TransactionTypes::Deposit),
+            client: Some(1),
+            tx: Some(1),
+            amount: Some(dec!(1.0)),
+        };
+        let rejection = Rejection::new(2, &record, TransactionError::DuplicateTx).with_source("a.csv");
+        let mut buffer = Vec::new();
+        let mut writer = RejectWriter::new(RejectFormat::Csv, &mut buffer);
+        writer.write(&rejection).unwrap();
+        writer.write(&Rejection { source: None, ..rejection }).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        // Every row has the same columns, whether or not it has a source
+        assert_eq!(
+            "source,line,type,client,tx,amount,reason\na.csv,2,deposit,1,1,1.0,duplicate transaction id\n,2,deposit,1,1,1.0,duplicate transaction id\n",
+            String::from_utf8(buffer).unwrap()
+        );
+    }
+
+    async fn merged(inputs: [&'static str; 2], order: MergeOrder) -> Vec<(Option<u32>, u64)> {
+        let mut receivers = Vec::new();
+        for input in inputs {
+            let (tx, rx) = tokio::sync::mpsc::channel(1);
+            tokio::spawn(read_csv(input.as_bytes(), tx, false));
+            receivers.push(rx);
+        }
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        tokio::spawn(merge(receivers, order, tx));
+
+        let mut received = Vec::new();
+        while let Some(ingested) = rx.recv().await {
+            if let Ingested::Record { line, record, .. } = ingested {
+                received.push((record.tx, line));
+            }
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn merge_orders_sources_by_tx_or_timestamp() {
+        let first = "type, client, tx, amount, timestamp\n\
+                     deposit, 1, 1, 1.0, 30\n\
+                     deposit, 1, 4, 1.0, 10\n";
+        let second = "type, client, tx, amount, timestamp\n\
+                      dispute, 1, 1, , 20\n\
+                      deposit, 2, 2, 1.0, 40\n\
+                      deposit, 2, 3, 1.0, 50\n";
+
+        assert_eq!(
+            vec![(Some(1), 2), (Some(1), 2), (Some(2), 3), (Some(3), 4), (Some(4), 3)],
+            merged([first, second], MergeOrder::Tx).await
+        );
+        // The first source is not in timestamp order, so it is only merged as far
+        // as its head allows
+        assert_eq!(
+            vec![(Some(1), 2), (Some(1), 2), (Some(4), 3), (Some(2), 3), (Some(3), 4)],
+            merged([second, first], MergeOrder::Timestamp).await
+        );
+    }
+
+    #[test]
+    fn dispute_window_holds_early_disputes_until_their_transaction_arrives() {
+        let mut transaction_processor = Transactor::new();
+        let mut dispute_window = DisputeWindow::new(2);
+
+        let record = |transaction_type, tx, amount| Transaction {
+            transaction_type: Some(transaction_type),
+            client: Some(1),
+            tx: Some(tx),
+            amount,
+        };
+
+        let resolve = record(TransactionTypes::Resolve, 1, None);
+        let dispute = record(TransactionTypes::Dispute, 1, None);
+        assert!(dispute_window.process(&mut transaction_processor, None, 1, resolve).is_empty());
+        assert!(dispute_window.process(&mut transaction_processor, None, 2, dispute).is_empty());
+        assert_eq!(2, dispute_window.held());
+
+        // The deposit makes the dispute valid, which in turn makes the resolve valid
+        let deposit = record(TransactionTypes::Deposit, 1, Some(dec!(4.0)));
+        let settled = dispute_window.process(&mut transaction_processor, None, 3, deposit);
+        let settled: Vec<_> = settled.into_iter().map(|settled| (settled.line, settled.result)).collect();
+        assert_eq!(vec![(3, Ok(Outcome::Deposited)), (2, Ok(Outcome::Disputed)), (1, Ok(Outcome::Resolved))], settled);
+        assert_eq!(0, dispute_window.held());
+        assert_eq!(Some(dec!(4.0)), Some(transaction_processor.balance(1).unwrap().available));
+
+        // A record which never becomes valid is rejected once its window runs out
+        let chargeback = record(TransactionTypes::Chargeback, 9, None);
+        let withdrawal = record(TransactionTypes::Withdrawal, 2, Some(dec!(1.0)));
+        assert!(dispute_window.process(&mut transaction_processor, None, 4, chargeback).is_empty());
+        assert_eq!(1, dispute_window.process(&mut transaction_processor, None, 5, withdrawal).len());
+        let settled = dispute_window.process(&mut transaction_processor, None, 6, record(TransactionTypes::Dispute, 2, None));
+        assert_eq!(vec![Ok(Outcome::Disputed), Err(TransactionError::UnknownTx)], settled.into_iter().map(|settled| settled.result).collect::<Vec<_>>());
+
+        let late = record(TransactionTypes::Dispute, 7, None);
+        assert!(dispute_window.process(&mut transaction_processor, None, 7, late).is_empty());
+        let settled = dispute_window.finish();
+        assert_eq!(1, settled.len());
+        assert_eq!(Err(TransactionError::UnknownTx), settled[0].result);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn sharded_engine_matches_a_single_transactor() {
+        let mut records = Vec::new();
+        for client in 1..=20u16 {
+            let tx = u32::from(client) * 10;
+            records.push((TransactionTypes::Deposit, client, tx, Some(dec!(10.0))));
+            records.push((TransactionTypes::Withdrawal, client, tx + 1, Some(dec!(2.5))));
+            records.push((TransactionTypes::Dispute, client, tx, None));
+            if client % 2 == 0 {
+                records.push((TransactionTypes::Chargeback, client, tx, None));
+            }
+        }
+        // A tx id reused by clients on another shard, which are rejected for
+        // their own account first (client 2 is locked, client 21 has none), and
+        // a dispute of another shard's tx
+        records.push((TransactionTypes::Deposit, 2, 30, Some(dec!(1.0))));
+        records.push((TransactionTypes::Deposit, 1, 40, Some(dec!(1.0))));
+        records.push((TransactionTypes::Withdrawal, 21, 40, Some(dec!(1.0))));
+        records.push((TransactionTypes::Dispute, 4, 11, None));
+        // The same on one shard
+        records.push((TransactionTypes::Deposit, 7, 30, Some(dec!(1.0))));
+        records.push((TransactionTypes::Resolve, 7, 30, None));
+
+        let records: Vec<_> = records
+            .into_iter()
+            .map(|(transaction_type, client, tx, amount)| Transaction {
+                transaction_type: Some(transaction_type),
+                client: Some(client),
+                tx: Some(tx),
+                amount,
+            })
+            .collect();
+
+        let mut single = Transactor::new();
+        let mut single_rejected = Vec::new();
+        for (line, record) in records.iter().enumerate() {
+            if let Err(e) = single.process_a_record(*record) {
+                single_rejected.push((line as u64, e));
+            }
+        }
+
+        let (mut engine, mut rejected) = ShardedEngine::spawn(4, Transactor::new);
+        let mut sharded_rejected = Vec::new();
+        for (line, record) in records.iter().enumerate() {
+            if let Some(settled) = engine.process(None, line as u64, *record).await {
+                sharded_rejected.push((settled.line, settled.result.unwrap_err()));
+            }
+        }
+        let mut sharded = Transactor::new();
+        engine.finish(&mut sharded).await.unwrap();
+        while let Some(settled) = rejected.recv().await {
+            sharded_rejected.push((settled.line, settled.result.unwrap_err()));
+        }
+        sharded_rejected.sort_by_key(|(line, _)| *line);
+
+        assert_eq!(single_rejected, sharded_rejected);
+        assert_eq!(
+            single.accounts().map(|(client, account)| (client, account.balance())).collect::<Vec<_>>(),
+            sharded.accounts().map(|(client, account)| (client, account.balance())).collect::<Vec<_>>()
+        );
+        assert_eq!(single.transaction(2, 20).unwrap(), sharded.transaction(2, 20).unwrap());
+    }
+
+    #[tokio::test]
+    async fn server_feeds_records_and_answers_balance_queries() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (records_tx, mut records) = tokio::sync::mpsc::channel(32);
+        let (queries_tx, mut queries) = tokio::sync::mpsc::channel(32);
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, records_tx, queries_tx, async {
+            let _ = stopped.await;
+        }));
+
+        // The consumer, as run by the binary
+        let consumer = tokio::spawn(async move {
+            let mut transaction_processor = Transactor::new();
+            let mut rejected = Vec::new();
+            loop {
+                tokio::select! {
+                    biased;
+                    received = records.recv() => match received {
+                        Some(Ingested::Record { record, .. }) => {
+                            let _ = transaction_processor.process_a_record(record);
+                        }
+                        Some(Ingested::Rejected(rejection)) => rejected.push(rejection),
+                        None => break,
+                    },
+                    Some(query) = queries.recv() => {
+                        query.answer(&mut transaction_processor);
+                    }
+                }
+            }
+            (transaction_processor, rejected)
+        });
+
+        let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(b"type, client, tx, amount\ndeposit, 1, 1, 5.5\n{\"type\": \"withdrawal\", \"client\": 1, \"tx\": 2, \"amount\": \"1.25\"}\ndispute, 1, 2\nrefund, 1, 3, 1.0\ntype, client, tx, amount\nbalances\n")
+            .await
+            .unwrap();
+
+        let mut lines = BufReader::new(client).lines();
+        assert!(lines.next_line().await.unwrap().unwrap().starts_with("error: line 5: unknown variant `refund`"));
+        // Only the first line can be a header
+        assert!(lines.next_line().await.unwrap().unwrap().starts_with("error: line 6: unknown variant `type`"));
+        assert_eq!(Some("client,available,held,total,locked".to_string()), lines.next_line().await.unwrap());
+        assert_eq!(Some("1,4.25,1.25,5.5,false".to_string()), lines.next_line().await.unwrap());
+        assert_eq!(Some(String::new()), lines.next_line().await.unwrap());
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        let (transaction_processor, rejected) = consumer.await.unwrap();
+        assert_eq!(Some(dec!(1.25)), Some(transaction_processor.balance(1).unwrap().held));
+        assert_eq!(2, rejected.len());
+        assert_eq!(5, rejected[0].line);
+        assert!(rejected[0].source.as_deref().unwrap().starts_with("tcp:127.0.0.1:"));
+    }
+
+    /// Sends a request to `app` and returns the status and body of its response.
+    #[cfg(feature = "http")]
+    async fn call(app: &axum::Router, method: &str, uri: &str, body: &str) -> (u16, String) {
+        use http_body_util::BodyExt;
+        use tower::ServiceExt;
+
+        let request = axum::http::Request::builder().method(method).uri(uri).header("content-type", "application/json").body(axum::body::Body::from(body.to_string())).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status().as_u16();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[cfg(feature = "http")]
+    #[tokio::test]
+    async fn http_api_processes_records_and_answers_queries() {
+        let (queries_tx, mut queries) = tokio::sync::mpsc::channel::<Query>(32);
+        let consumer = tokio::spawn(async move {
+            let mut transaction_processor = Transactor::new();
+            let mut settled = Vec::new();
+            while let Some(query) = queries.recv().await {
+                settled.extend(query.answer(&mut transaction_processor));
+            }
+            (transaction_processor, settled)
+        });
+        let app = router(queries_tx);
+
+        assert_eq!((200, r#"{"outcome":"deposited"}"#.to_string()), call(&app, "POST", "/transactions", r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "5.5"}"#).await);
+        assert_eq!((200, r#"{"outcome":"deposited"}"#.to_string()), call(&app, "POST", "/transactions", r#"{"type": "deposit", "client": 2, "tx": 2, "amount": "3"}"#).await);
+        assert_eq!((200, r#"{"outcome":"disputed"}"#.to_string()), call(&app, "POST", "/transactions", r#"{"type": "dispute", "client": 1, "tx": 1}"#).await);
+        assert_eq!((422, r#"{"error":"insufficient available funds"}"#.to_string()), call(&app, "POST", "/transactions", r#"{"type": "withdrawal", "client": 2, "tx": 3, "amount": "4"}"#).await);
+        assert_eq!((422, r#"{"error":"record is missing its tx"}"#.to_string()), call(&app, "POST", "/transactions", r#"{"type": "deposit", "client": 2}"#).await);
+        assert_eq!(422, call(&app, "POST", "/transactions", r#"{"type": "refund"}"#).await.0);
+        assert_eq!((422, r#"{"error":"EOF while parsing a string at line 1 column 17"}"#.to_string()), call(&app, "POST", "/transactions", r#"{"type": "deposit"#).await);
+        assert_eq!((200, r#"{"outcome":"deposited"}"#.to_string()), call(&app, "POST", "/transactions", r#"{"type": "deposit", "client": 4, "tx": 4, "amount": 1234567890123456.1234}"#).await);
+
+        assert_eq!((200, r#"{"client":1,"available":"0","held":"5.5","total":"5.5","locked":false}"#.to_string()), call(&app, "GET", "/accounts/1", "").await);
+        assert_eq!(404, call(&app, "GET", "/accounts/3", "").await.0);
+        assert_eq!((200, r#"[{"type":"deposit","client":1,"tx":1,"amount":"5.5","state":"disputed"}]"#.to_string()), call(&app, "GET", "/accounts/1/transactions", "").await);
+        assert_eq!((404, r#"{"error":"unknown client 3"}"#.to_string()), call(&app, "GET", "/accounts/3/transactions", "").await);
+        assert_eq!((200, r#"[{"client":1,"tx":1}]"#.to_string()), call(&app, "GET", "/disputes", "").await);
+
+        drop(app);
+        let (transaction_processor, settled) = consumer.await.unwrap();
+        assert_eq!(Some(dec!(3)), Some(transaction_processor.balance(2).unwrap().available));
+        assert_eq!(Some(dec!(1234567890123456.1234)), Some(transaction_processor.balance(4).unwrap().available));
+        // Only records which passed validation reach the consumer
+        assert_eq!(5, settled.len());
+        assert_eq!(Some("http"), settled[3].source.as_deref());
+        assert_eq!(Err(TransactionError::InsufficientFunds), settled[3].result);
+    }
+
+    #[tokio::test]
+    async fn json_and_json_lines_inputs_are_validated_like_csv() {
+        async fn read(input: &str, format: InputFormat) -> Vec<Ingested> {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(32);
+            read_input(input.as_bytes(), format, tx, false).await.unwrap();
+            let mut received = Vec::new();
+            while let Some(ingested) = rx.recv().await {
+                received.push(ingested);
+            }
+            received
+        }
+
+        let jsonl = "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": \"1.5\", \"timestamp\": 7}\n\n{\"type\": \"deposit\", \"client\": 1, \"tx\": 2, \"amount\": \"abc\"}\n{\"type\": \"dispute\", \"tx\": 1}\n{\"type\": \"withdrawal\", \"client\": 1, \"tx\": 3, \"amount\": 0.5}\n";
+        for format in [InputFormat::JsonLines, InputFormat::Auto] {
+            let received = read(jsonl, format).await;
+            assert_eq!(4, received.len());
+            assert!(matches!(&received[0], Ingested::Record { line: 1, timestamp: Some(7), record, .. } if record.amount == Some(dec!(1.5))));
+            assert!(matches!(&received[1], Ingested::Rejected(Rejection { line: 3, tx: None, .. })));
+            assert!(matches!(&received[2], Ingested::Rejected(Rejection { line: 4, tx: Some(1), reason, .. }) if reason == "record is missing its client"));
+            assert!(matches!(&received[3], Ingested::Record { line: 5, timestamp: None, record, .. } if record.amount == Some(dec!(0.5))));
+        }
+
+        let json = "  [{\"type\": \"deposit\", \"client\": 2, \"tx\": 1, \"amount\": \"2\"}, {\"type\": \"deposit\", \"client\": 2}]";
+        for format in [InputFormat::Json, InputFormat::Auto] {
+            let received = read(json, format).await;
+            assert_eq!(2, received.len());
+            assert!(matches!(&received[0], Ingested::Record { line: 1, .. }));
+            assert!(matches!(&received[1], Ingested::Rejected(Rejection { line: 2, .. })));
+        }
+
+        // A broken array cannot be read past, even outside strict mode
+        let (tx, _rx) = tokio::sync::mpsc::channel(32);
+        assert!(matches!(read_input("[{".as_bytes(), InputFormat::Json, tx, false).await, Err(IngestError::Malformed { .. })));
+        let (tx, _rx) = tokio::sync::mpsc::channel(32);
+        assert!(matches!(read_input("{\"tx\": 1}\n".as_bytes(), InputFormat::JsonLines, tx, true).await, Err(IngestError::Malformed { line: 1, .. })));
+
+        assert_eq!(1, read("type, client, tx, amount\ndeposit, 1, 1, 1.0\n", InputFormat::Auto).await.len());
+        assert_eq!(InputFormat::JsonLines, InputFormat::from_path("in.ndjson"));
+        assert_eq!(InputFormat::Auto, InputFormat::from_path("in.txt"));
+        assert_eq!(Ok(InputFormat::JsonLines), "jsonl".parse());
+        assert!("xml".parse::<InputFormat>().is_err());
+    }
+
+    #[cfg(feature = "parquet")]
+    #[tokio::test]
+    async fn parquet_inputs_are_read_and_the_report_written_back() {
+        use std::sync::Arc;
+
+        use arrow_array::{cast::AsArray, types::Decimal128Type, ArrayRef, Float64Array, Int64Array, RecordBatch, StringArray};
+        use parquet::arrow::{arrow_reader::ParquetRecordBatchReader, ArrowWriter};
+
+        let columns: Vec<(&str, ArrayRef)> = vec![
+            ("type", Arc::new(StringArray::from(vec![Some("deposit"), Some("deposit"), Some("withdrawal"), Some("refund"), None]))),
+            ("client", Arc::new(Int64Array::from(vec![1, 2, 1, 1, 2]))),
+            ("tx", Arc::new(Int64Array::from(vec![1, 2, 3, 4, 5]))),
+            ("amount", Arc::new(Float64Array::from(vec![Some(2.5), Some(1.0), Some(0.25), Some(1.0), None]))),
+            ("timestamp", Arc::new(Int64Array::from(vec![10, 20, 30, 40, -1]))),
+        ];
+        let batch = RecordBatch::try_from_iter(columns).unwrap();
+        let mut input = Vec::new();
+        let mut writer = ArrowWriter::try_new(&mut input, batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+
+        // Recognised by its magic number
+        let (tx, mut rx) = tokio::sync::mpsc::channel(32);
+        read_input(input.as_slice(), InputFormat::Auto, tx, false).await.unwrap();
+        let mut transaction_processor = Transactor::new();
+        let mut rejected = Vec::new();
+        while let Some(ingested) = rx.recv().await {
+            match ingested {
+                Ingested::Record { record, timestamp, line, .. } => {
+                    assert_eq!(Some(line * 10), timestamp);
+                    transaction_processor.process_a_record(record).unwrap();
+                }
+                Ingested::Rejected(rejection) => rejected.push(rejection),
+            }
+        }
+        assert_eq!(2, rejected.len());
+        assert_eq!(4, rejected[0].line);
+        assert!(rejected[0].reason.contains("unknown variant `refund`"));
+        assert_eq!(5, rejected[1].line);
+        assert_eq!(Some(5), rejected[1].tx);
+
+        let rows: Vec<_> = transaction_processor.accounts().map(|(client, account)| AccountRow::new(client, account)).collect();
+        let mut output = Vec::new();
+        write_rows("parquet".parse().unwrap(), rows, &mut output).unwrap();
+
+        let batch = ParquetRecordBatchReader::try_new(bytes::Bytes::from(output), 1024).unwrap().next().unwrap().unwrap();
+        let names: Vec<_> = batch.schema().fields().iter().map(|field| field.name().clone()).collect();
+        assert_eq!(vec!["client", "available", "held", "total", "locked"], names);
+        let available = batch.column(1).as_primitive::<Decimal128Type>();
+        assert_eq!("2.2500", available.value_as_string(0));
+        assert_eq!("1.0000", available.value_as_string(1));
+        assert!(!batch.column(4).as_boolean().value(0));
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn parquet_report_is_written_in_batches() {
+        use arrow_array::{cast::AsArray, types::UInt16Type};
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReader;
+
+        let mut transaction_processor = Transactor::new();
+        for client in 1..=2500 {
+            transaction_processor.process_a_record(Transaction { transaction_type: Some(TransactionTypes::Deposit), client: Some(client), tx: Some(client.into()), amount: Some(dec!(1)) }).unwrap();
+        }
+
+        for count in [0, 2500] {
+            let rows: Vec<_> = transaction_processor.accounts().take(count).map(|(client, account)| AccountRow::new(client, account)).collect();
+            let mut output = Vec::new();
+            write_rows("parquet".parse().unwrap(), rows, &mut output).unwrap();
+
+            // Every row is written, whether it ends a full batch or not
+            let mut clients = Vec::new();
+            for batch in ParquetRecordBatchReader::try_new(bytes::Bytes::from(output), 1024).unwrap() {
+                clients.extend(batch.unwrap().column(0).as_primitive::<UInt16Type>().values().iter().copied());
+            }
+            clients.sort_unstable();
+            assert_eq!((1..=count as u16).collect::<Vec<_>>(), clients);
+        }
+    }
+
+    #[test]
+    fn records_are_validated_per_type() {
+        let record = |transaction_type, amount| Transaction {
+            transaction_type: Some(transaction_type),
+            client: Some(1),
+            tx: Some(1),
+            amount,
+        };
+
+        assert_eq!(Ok(()), validate(&record(TransactionTypes::Deposit, Some(dec!(1.2345)))));
+        assert_eq!(Ok(()), validate(&record(TransactionTypes::Withdrawal, Some(dec!(1.50000)))));
+        assert_eq!(Err(ValidationError::ZeroAmount), validate(&record(TransactionTypes::Deposit, Some(dec!(0)))));
+        assert_eq!(Ok(()), validate(&record(TransactionTypes::Resolve, None)));
+        assert_eq!(Err(ValidationError::MissingAmount), validate(&record(TransactionTypes::Withdrawal, None)));
+        assert_eq!(Err(ValidationError::UnexpectedAmount), validate(&record(TransactionTypes::Dispute, Some(dec!(1)))));
+        assert_eq!(Err(ValidationError::NegativeAmount), validate(&record(TransactionTypes::Deposit, Some(dec!(-50)))));
+        assert_eq!(Err(ValidationError::ExcessPrecision { scale: 4 }), validate(&record(TransactionTypes::Deposit, Some(dec!(1.23456)))));
+        assert_eq!(Err(ValidationError::MissingType), validate(&Transaction { transaction_type: None, ..record(TransactionTypes::Deposit, None) }));
+        assert_eq!(Err(ValidationError::MissingTx), validate(&Transaction { tx: None, ..record(TransactionTypes::Dispute, None) }));
+
+        // The engine enforces the same rules, a negative deposit no longer withdraws
+        let mut transaction_processor = Transactor::new();
+        transaction_processor.process_a_record(record(TransactionTypes::Deposit, Some(dec!(5)))).unwrap();
+        let negative = Transaction { tx: Some(2), ..record(TransactionTypes::Deposit, Some(dec!(-50))) };
+        assert_eq!(Err(TransactionError::Invalid(ValidationError::NegativeAmount)), transaction_processor.process_a_record(negative));
+        assert_eq!("amount has more than 4 decimal places", TransactionError::Invalid(ValidationError::ExcessPrecision { scale: 4 }).to_string());
+        assert_eq!(Some(dec!(5)), Some(transaction_processor.balance(1).unwrap().available));
+    }
+
+    #[test]
+    fn amount_policy_rounds_or_rejects_and_caps_amounts() {
+        let deposit = |tx, amount| Transaction {
+            transaction_type: Some(TransactionTypes::Deposit),
+            client: Some(1),
+            tx: Some(tx),
+            amount: Some(amount),
+        };
+
+        let mut transaction_processor = Transactor::new().with_amount_policy(AmountPolicy {
+            scale: 2,
+            rounding: Rounding::Round,
+            max_amount: Some(dec!(100)),
+        });
+        assert_eq!(Ok(Outcome::Deposited), transaction_processor.process_a_record(deposit(1, dec!(10.125))));
+        assert_eq!(Ok(Outcome::Deposited), transaction_processor.process_a_record(deposit(2, dec!(100))));
+        assert_eq!(Err(TransactionError::Invalid(ValidationError::AmountTooLarge { max: dec!(100) })), transaction_processor.process_a_record(deposit(3, dec!(100.01))));
+        assert_eq!(Err(TransactionError::Invalid(ValidationError::ZeroAmount)), transaction_processor.process_a_record(deposit(4, dec!(0.004))));
+        assert_eq!(Err(TransactionError::Invalid(ValidationError::NegativeAmount)), transaction_processor.process_a_record(deposit(5, dec!(-1))));
+
+        // The ledger keeps the rounded amount, so a dispute holds what was deposited
+        assert_eq!(Some(dec!(110.12)), Some(transaction_processor.balance(1).unwrap().available));
+        assert_eq!(Some(dec!(10.12)), transaction_processor.transaction(1, 1).unwrap().map(|entry| entry.amount));
+
+        let mut transaction_processor = Transactor::new().with_amount_policy(AmountPolicy { scale: 2, ..AmountPolicy::default() });
+        assert_eq!(Err(TransactionError::Invalid(ValidationError::ExcessPrecision { scale: 2 })), transaction_processor.process_a_record(deposit(1, dec!(10.125))));
+        assert_eq!(Ok(Outcome::Deposited), transaction_processor.process_a_record(deposit(1, dec!(10.120))));
+    }
+
+    #[test]
+    fn raw_records_are_checked_into_commands() {
+        let raw = RawTransaction { transaction_type: Some(TransactionTypes::Withdrawal), client: Some(3), tx: Some(7), amount: Some(dec!(2.5)) };
+        let command = Command::try_from(raw).unwrap();
+        assert_eq!(Command::Withdrawal { client: 3, tx: 7, amount: dec!(2.5) }, command);
+        assert_eq!((3, 7, Some(dec!(2.5))), (command.client(), command.tx(), command.amount()));
+        assert_eq!(raw, RawTransaction::from(command));
+
+        let dispute = RawTransaction { transaction_type: Some(TransactionTypes::Dispute), amount: None, ..raw };
+        assert_eq!(Ok(Command::Dispute { client: 3, tx: 7 }), Command::try_from(dispute));
+        assert_eq!(Err(ValidationError::UnexpectedAmount), Command::try_from(RawTransaction { amount: Some(dec!(1)), ..dispute }));
+        assert_eq!(Err(ValidationError::MissingAmount), Command::try_from(RawTransaction { amount: None, ..raw }));
+        assert_eq!(Err(ValidationError::MissingClient), Command::try_from(RawTransaction { client: None, ..raw }));
+
+        // Commands can be processed directly, their amounts still go through the policy
+        let mut transaction_processor = Transactor::new();
+        assert_eq!(Ok(Outcome::Deposited), transaction_processor.process(Command::Deposit { client: 3, tx: 1, amount: dec!(10) }));
+        assert_eq!(Ok(Outcome::Withdrawn), transaction_processor.process(command));
+        assert_eq!(Err(TransactionError::Invalid(ValidationError::NegativeAmount)), transaction_processor.process(Command::Deposit { client: 3, tx: 2, amount: dec!(-1) }));
+        assert_eq!(Ok(Outcome::Disputed), transaction_processor.process(Command::Dispute { client: 3, tx: 7 }));
+        // The disputed withdrawal is held, so it counts towards the total again
+        assert_eq!(Some((dec!(7.5), dec!(2.5), dec!(10))), transaction_processor.balance(3).map(|b| (b.available, b.held, b.total)));
+    }
+
+    /// Any record at all, with or without each of its fields. Clients and txs
+    /// come from a small range so that records often refer to each other.
+    fn arbitrary_record() -> impl Strategy<Value = Transaction> {
+        let transaction_type = proptest::option::weighted(0.95, select(vec![TransactionTypes::Deposit, TransactionTypes::Withdrawal, TransactionTypes::Dispute, TransactionTypes::Resolve, TransactionTypes::Chargeback]));
+        let amount = prop_oneof![
+            8 => (-1_000_000_000_000i64..1_000_000_000_000, 0..6u32).prop_map(|(mantissa, scale)| Decimal::new(mantissa, scale)),
+            // Close enough to the largest Decimal that a few of them overflow an account
+            1 => (0..1_000u32).prop_map(|n| Decimal::MAX - Decimal::from(n)),
+            // Any Decimal at all, of any size and scale
+            1 => (any::<[u32; 3]>(), any::<bool>(), 0..=28u32).prop_map(|([lo, mid, hi], negative, scale)| Decimal::from_parts(lo, mid, hi, negative, scale)),
+        ];
+        (transaction_type, proptest::option::weighted(0.95, 0..4u16), proptest::option::weighted(0.95, 0..32u32), proptest::option::weighted(0.9, amount))
+            .prop_map(|(transaction_type, client, tx, amount)| Transaction { transaction_type, client, tx, amount })
+    }
+
+    proptest! {
+        #[test]
+        fn engine_never_panics_and_balances_add_up(
+            records in proptest::collection::vec(arbitrary_record(), 0..200),
+            lock_policy in select(vec![LockPolicy::Ignore, LockPolicy::FreezeFunds, LockPolicy::FreezeAll]),
+            dispute_policy in select(vec![DisputePolicy::DirectionAware, DisputePolicy::Uniform]),
+            rounding in select(vec![Rounding::Reject, Rounding::Round]),
+        ) {
+            let mut transaction_processor = Transactor::new().with_lock_policy(lock_policy).with_dispute_policy(dispute_policy).with_amount_policy(AmountPolicy { rounding, ..AmountPolicy::default() });
+            let balances = |transaction_processor: &Transactor| transaction_processor.accounts().map(|(client, account)| (client, account.balance())).collect::<Vec<_>>();
+
+            for record in records {
+                let before = balances(&transaction_processor);
+                let result = transaction_processor.process_a_record(record);
+
+                // A rejected record leaves every account as it was
+                if result.is_err() {
+                    prop_assert_eq!(&before, &balances(&transaction_processor));
+                }
+                for (_, balance) in balances(&transaction_processor) {
+                    prop_assert_eq!(Some(balance.total), balance.available.checked_add(balance.held));
+                    prop_assert!(balance.held >= Decimal::ZERO);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn amounts_overflowing_an_account_are_rejected() {
+        let record = |transaction_type, tx, amount| Transaction { transaction_type: Some(transaction_type), client: Some(1), tx: Some(tx), amount };
+        let mut transaction_processor = Transactor::new();
+
+        assert_eq!(Ok(Outcome::Deposited), transaction_processor.process_a_record(record(TransactionTypes::Deposit, 1, Some(Decimal::MAX))));
+        assert_eq!(Err(TransactionError::ArithmeticOverflow), transaction_processor.process_a_record(record(TransactionTypes::Deposit, 2, Some(dec!(1)))));
+        assert_eq!("amount would overflow the account", TransactionError::ArithmeticOverflow.to_string());
+        // Neither the account nor the ledger took the rejected deposit
+        assert_eq!(Some(Decimal::MAX), transaction_processor.balance(1).map(|b| b.total));
+        assert_eq!(None, transaction_processor.transaction(1, 2).unwrap());
+
+        // Disputing a withdrawal holds its amount on top of the total, which is already at the maximum
+        assert_eq!(Ok(Outcome::Withdrawn), transaction_processor.process_a_record(record(TransactionTypes::Withdrawal, 3, Some(dec!(1)))));
+        assert_eq!(Ok(Outcome::Deposited), transaction_processor.process_a_record(record(TransactionTypes::Deposit, 4, Some(dec!(1)))));
+        assert_eq!(Err(TransactionError::ArithmeticOverflow), transaction_processor.process_a_record(record(TransactionTypes::Dispute, 3, None)));
+        assert_eq!(DisputeState::Processed, transaction_processor.transaction(1, 3).unwrap().unwrap().state);
+        assert!(transaction_processor.open_disputes(1).is_empty());
+
+        // Everything can still be withdrawn, and then deposited again
+        assert_eq!(Ok(Outcome::Withdrawn), transaction_processor.process_a_record(record(TransactionTypes::Withdrawal, 5, Some(Decimal::MAX))));
+        assert_eq!(Ok(Outcome::Deposited), transaction_processor.process_a_record(record(TransactionTypes::Deposit, 6, Some(Decimal::MAX))));
+        assert_eq!(Some((Decimal::MAX, dec!(0), Decimal::MAX)), transaction_processor.balance(1).map(|b| (b.available, b.held, b.total)));
+
+        // Near the limit a Decimal would round the fraction away instead of overflowing
+        assert_eq!(Err(TransactionError::ArithmeticOverflow), transaction_processor.process_a_record(record(TransactionTypes::Deposit, 7, Some(dec!(0.5)))));
+        let mut transaction_processor = Transactor::new().with_dispute_policy(DisputePolicy::Uniform);
+        transaction_processor.process_a_record(record(TransactionTypes::Deposit, 1, Some(Decimal::MAX))).unwrap();
+        transaction_processor.process_a_record(record(TransactionTypes::Withdrawal, 2, Some(Decimal::MAX))).unwrap();
+        transaction_processor.process_a_record(record(TransactionTypes::Deposit, 3, Some(dec!(0.1)))).unwrap();
+        assert_eq!(Err(TransactionError::ArithmeticOverflow), transaction_processor.process_a_record(record(TransactionTypes::Dispute, 2, None)));
+        assert_eq!(Some((dec!(0.1), dec!(0), dec!(0.1))), transaction_processor.balance(1).map(|b| (b.available, b.held, b.total)));
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn parquet_report_keeps_amounts_at_decimal_max() {
+        use arrow_array::{cast::AsArray, types::Decimal128Type};
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReader;
+
+        let mut transaction_processor = Transactor::new();
+        transaction_processor.process_a_record(Transaction { transaction_type: Some(TransactionTypes::Deposit), client: Some(1), tx: Some(1), amount: Some(Decimal::MAX) }).unwrap();
+
+        let rows: Vec<_> = transaction_processor.accounts().map(|(client, account)| AccountRow::new(client, account)).collect();
+        let mut output = Vec::new();
+        write_rows("parquet".parse().unwrap(), rows, &mut output).unwrap();
+
+        let batch = ParquetRecordBatchReader::try_new(bytes::Bytes::from(output), 1024).unwrap().next().unwrap().unwrap();
+        assert_eq!(format!("{}.0000", Decimal::MAX), batch.column(3).as_primitive::<Decimal128Type>().value_as_string(0));
+    }
+
+    #[cfg(feature = "disk_ledger")]
+    #[test]
+    fn disk_ledger_file_stays_small() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger.redb");
+
+        let mut transaction_processor = Transactor::with_ledger(DiskLedger::open(&path).unwrap());
+        for tx in 1..=5_000 {
+            transaction_processor.process_a_record(Transaction { transaction_type: Some(TransactionTypes::Deposit), client: Some(1), tx: Some(tx), amount: Some(dec!(1.5)) }).unwrap();
+            if tx % 2 == 0 {
+                transaction_processor.process_a_record(Transaction { transaction_type: Some(TransactionTypes::Dispute), client: Some(1), tx: Some(tx), amount: None }).unwrap();
+            }
+        }
+        transaction_processor.flush_ledger().unwrap();
+        assert_eq!(Some(dec!(3750)), transaction_processor.balance(1).map(|b| b.held));
+        drop(transaction_processor);
+
+        // Committing every write on its own took tens of kilobytes per record
+        let len = std::fs::metadata(&path).unwrap().len();
+        assert!(len < 4 * 1024 * 1024, "ledger file is {len} bytes");
+
+        let mut ledger = DiskLedger::open(&path).unwrap();
+        assert!(!ledger.is_empty().unwrap());
+        assert_eq!(5_000, ledger.entries().unwrap().count());
+        ledger.clear().unwrap();
+        assert!(ledger.is_empty().unwrap());
+    }
+
+    #[tokio::test]
+    async fn json_numeric_amounts_keep_their_precision() {
+        let jsonl = "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 1234567890123456.1234}\n";
+        let json = "[{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 1234567890123456.1234, \"timestamp\": 3}]";
+        for input in [jsonl, json] {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(32);
+            read_input(input.as_bytes(), InputFormat::Auto, tx, false).await.unwrap();
+            // Read as an f64 the amount would lose its decimal places
+            assert!(matches!(rx.recv().await, Some(Ingested::Record { record, .. }) if record.amount == Some(dec!(1234567890123456.1234))));
+        }
+    }
+
+    #[cfg(feature = "parquet")]
+    #[tokio::test]
+    async fn parquet_files_reject_ids_out_of_range() {
+        use std::sync::Arc;
+
+        use arrow_array::{ArrayRef, Int64Array, RecordBatch, StringArray};
+        use parquet::arrow::ArrowWriter;
+
+        let columns: Vec<(&str, ArrayRef)> = vec![
+            ("type", Arc::new(StringArray::from(vec!["deposit", "deposit", "deposit", "deposit"]))),
+            ("client", Arc::new(Int64Array::from(vec![1, 70_000, 2, -1]))),
+            ("tx", Arc::new(Int64Array::from(vec![1, 2, 5_000_000_000, 4]))),
+            ("amount", Arc::new(StringArray::from(vec!["1.0", "1.0", "1.0", "1.0"]))),
+        ];
+        let batch = RecordBatch::try_from_iter(columns).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.parquet");
+        let mut writer = ArrowWriter::try_new(std::fs::File::create(&path).unwrap(), batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(32);
+        read_source(&Source::Path(path), InputFormat::Auto, tx, false).await.unwrap();
+
+        match rx.recv().await {
+            Some(Ingested::Record { line: 1, record, .. }) => assert_eq!(Some(1), record.client),
+            other => panic!("expected a record, got {other:?}"),
+        }
+        for (line, reason) in [(2, "invalid client"), (3, "invalid tx"), (4, "invalid client")] {
+            match rx.recv().await {
+                Some(Ingested::Rejected(rejection)) => {
+                    assert_eq!(line, rejection.line);
+                    assert!(rejection.reason.contains(reason), "{}", rejection.reason);
+                    assert!(rejection.reason.contains("Can't cast value"), "{}", rejection.reason);
+                }
+                other => panic!("expected a rejection, got {other:?}"),
+            }
+        }
+        assert_eq!(None, rx.recv().await);
+    }
+
+    #[test]
+    fn wal_logs_the_position_of_every_record_but_replays_only_accepted_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.jsonl");
+        let record = |transaction_type, tx, amount| Transaction { transaction_type: Some(transaction_type), client: Some(1), tx: Some(tx), amount: Some(amount) };
+
+        let mut transaction_processor = Transactor::new();
+        let (mut wal, _) = WriteAheadLog::recover(&path, FsyncPolicy::Always, &mut transaction_processor, 0).unwrap();
+        let records = [record(TransactionTypes::Deposit, 1, dec!(10)), record(TransactionTypes::Withdrawal, 2, dec!(15)), record(TransactionTypes::Deposit, 3, dec!(10))];
+        for (line, trans) in (2..).zip(records) {
+            let accepted = transaction_processor.process_a_record(trans).is_ok();
+            wal.append(Some("in.csv"), line, accepted.then_some(&trans)).unwrap();
+        }
+        // A malformed row, which never reached the transactor, and lines of
+        // another input which were dealt with out of order
+        wal.append(Some("in.csv"), 5, None).unwrap();
+        wal.append(Some("other.csv"), 4, None).unwrap();
+        wal.append(Some("other.csv"), 2, None).unwrap();
+        drop(wal);
+
+        let mut recovered = Transactor::new();
+        let (wal, replayed) = WriteAheadLog::recover(&path, FsyncPolicy::Always, &mut recovered, 0).unwrap();
+        assert_eq!(2, replayed);
+        assert_eq!(Some(dec!(20)), recovered.balance(1).map(|b| b.available));
+
+        // The rejected withdrawal is only skipped, it is not applied again
+        assert!((2..=5).all(|line| wal.is_logged("in.csv", line)));
+        assert!(!wal.is_logged("in.csv", 1));
+        assert!(!wal.is_logged("in.csv", 6));
+        assert!(wal.is_logged("other.csv", 2) && wal.is_logged("other.csv", 4));
+        assert!(!wal.is_logged("other.csv", 3));
+        assert!(!wal.is_logged("in.jsonl", 2));
+    }
+
+    #[test]
+    fn crash_between_saving_a_snapshot_and_resetting_the_wal_is_recovered() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.jsonl");
+        let deposit = |tx| Transaction { transaction_type: Some(TransactionTypes::Deposit), client: Some(1), tx: Some(tx), amount: Some(dec!(1.0)) };
+
+        let mut transaction_processor = Transactor::new();
+        let (mut wal, _) = WriteAheadLog::recover(&path, FsyncPolicy::Always, &mut transaction_processor, 0).unwrap();
+        for tx in 1..=3 {
+            transaction_processor.process_a_record(deposit(tx)).unwrap();
+            wal.append(Some("in.csv"), u64::from(tx) + 1, Some(&deposit(tx))).unwrap();
+        }
+        assert_eq!(3, wal.seq());
+        let mut snapshot = Vec::new();
+        transaction_processor.save_snapshot_at(&mut snapshot, wal.seq()).unwrap();
+        // The run crashes here, before the log is reset
+        drop(wal);
+
+        let mut restored = Transactor::new();
+        let wal_seq = restored.restore_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(3, wal_seq);
+        let (mut wal, replayed) = WriteAheadLog::recover(&path, FsyncPolicy::Always, &mut restored, wal_seq).unwrap();
+        assert_eq!(0, replayed);
+        assert_eq!(Some(dec!(3.0)), restored.balance(1).map(|b| b.total));
+        assert!(wal.is_logged("in.csv", 4));
+
+        // Numbering carries on past a reset, so later entries are replayed on top of the snapshot
+        wal.reset().unwrap();
+        restored.process_a_record(deposit(4)).unwrap();
+        wal.append(Some("in.csv"), 5, Some(&deposit(4))).unwrap();
+        assert_eq!(4, wal.seq());
+        drop(wal);
+
+        let mut recovered = Transactor::new();
+        let wal_seq = recovered.restore_snapshot(snapshot.as_slice()).unwrap();
+        let (_, replayed) = WriteAheadLog::recover(&path, FsyncPolicy::Always, &mut recovered, wal_seq).unwrap();
+        assert_eq!(1, replayed);
+        assert_eq!(Some(dec!(4.0)), recovered.balance(1).map(|b| b.total));
+
+        // A snapshot saved without a log replays all of it
+        let mut plain = Vec::new();
+        Transactor::new().save_snapshot(&mut plain).unwrap();
+        assert_eq!(0, Transactor::new().restore_snapshot(plain.as_slice()).unwrap());
+    }
+
+    #[cfg(feature = "http")]
+    #[tokio::test]
+    async fn http_storage_failures_are_server_errors() {
+        let (queries_tx, mut queries) = tokio::sync::mpsc::channel::<Query>(32);
+        tokio::spawn(async move {
+            let mut transaction_processor = Transactor::with_ledger(FailingLedger { ledger: MemoryLedger::default(), inserts: false });
+            while let Some(query) = queries.recv().await {
+                query.answer(&mut transaction_processor);
+            }
+        });
+        let app = router(queries_tx);
 
-        assert_eq!(Some(dec!(10.0)),Some(transaction_processor.client_accounts.get(&1).unwrap().available));
+        assert_eq!((500, r#"{"error":"ledger storage failed: disk full"}"#.to_string()), call(&app, "POST", "/transactions", r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1"}"#).await);
+        // Rejections of the record itself are still the client's to fix
+        assert_eq!(422, call(&app, "POST", "/transactions", r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": "1"}"#).await.0);
     }
 }
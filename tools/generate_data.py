@@ -18,10 +18,13 @@ with open('test_data_2.csv', 'w', newline='') as csvfile:
     # Write rows
     for i in range(num_rows):
         # Generate random data for each column
+        transaction_type = random.choice(types)
         row = [
-            random.choice(types),               # Type
+            transaction_type,                   # Type
             str(random.randint(0, 65535)),         # Client (using row index)
             str(start_tx + i),                  # TX (sequential)
-            round(random.uniform(10, 100), 5)   # Amount (random float)
+            # Amount (random float, at most 4 decimal places), only deposits
+            # and withdrawals have one
+            round(random.uniform(10, 100), 4) if transaction_type in ('deposit', 'withdrawal') else ''
         ]
         writer.writerow(row)